@@ -67,7 +67,13 @@ in
           add_header X-Content-Type-Options "nosniff" always;
           add_header X-XSS-Protection "1; mode=block" always;
         '';
-        locations."/".proxyPass = "http://unix:/var/lib/urlnao/urlnao.sock";
+        locations."/" = {
+          proxyPass = "http://unix:/var/lib/urlnao/urlnao.sock";
+          # recorded with each upload, overwritten so that clients cannot forge it
+          extraConfig = ''
+            proxy_set_header X-Remote-User $remote_user;
+          '';
+        };
       };
     };
 
@@ -98,7 +104,8 @@ in
         ProtectProc = "invisible";
         ProtectSystem = "full";
         RemoveIPC = true;
-        RestrictAddressFamilies = "AF_UNIX";
+        # TCP is needed for --listen tcp://… and --metrics-listen
+        RestrictAddressFamilies = "AF_UNIX AF_INET AF_INET6";
         RestrictNamespaces = true;
         RestrictSUIDSGID = true;
         SystemCallArchitectures = "native";
@@ -109,6 +116,7 @@ in
         StateDirectory = "urlnao";
         StateDirectoryMode = "0710";
         WorkingDirectory = "/var/lib/urlnao";
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         ExecStart = concatStringsSep " " ([
           "@${pkgs.urlnao}/bin/urlnao urlnao"
           "--hostname ${escapeShellArg cfg.hostname}"
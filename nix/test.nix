@@ -16,6 +16,15 @@ in
           enable = true;
           hostname = "server";
           protocol = "http";
+          extraArgs = [
+            "--listen" "unix:/var/lib/urlnao/urlnao.sock"
+            "--listen" "tcp://127.0.0.1:8080"
+            "--metrics-listen" "tcp://127.0.0.1:9100"
+            "--max-file-size" "64k"
+            "--max-upload-size" "96k"
+            "--max-files" "2"
+            "--default-ttl" "1d"
+          ];
         };
 
         # currently the default tokio runtime is used
@@ -23,6 +32,22 @@ in
         virtualisation.cores = 2;
       };
 
+      keyed = {
+        imports = [ ./module.nix ];
+
+        networking.firewall.allowedTCPPorts = [ 80 ];
+
+        custom.services.urlnao = {
+          enable = true;
+          hostname = "keyed";
+          protocol = "http";
+          extraArgs = [ "--require-api-key" "--api-key-file" "/var/lib/urlnao/keys.toml" ];
+        };
+
+        # keys in this file are read again on reload
+        systemd.tmpfiles.rules = [ "f /var/lib/urlnao/keys.toml 0640 urlnao urlnao -" ];
+      };
+
       client = {
         environment.systemPackages = [
           pkgs.curl
@@ -44,10 +69,259 @@ in
               '[ "200" -eq "$(curl -so /dev/null -w "%{response_code}" http://server/)" ]'
           )
 
+      with subtest("tcp listener"):
+          server.succeed("${pkgs.curl}/bin/curl -sSf http://127.0.0.1:8080/ | grep -q Urlnao")
+
       with subtest("upload and download"):
           client.succeed("head -c 235 /dev/urandom > testfile.bin")
           client.succeed("curl -sSf -F file=@testfile.bin http://server/up > url")
           client.succeed("xargs <url curl -L -sv --output download.bin >&2")
           client.succeed("cmp testfile.bin download.bin")
+
+      with subtest("range requests"):
+          client.succeed("xargs <url curl -L -sSf -r 10-19 --output range.bin")
+          client.succeed("cmp range.bin <(tail -c +11 testfile.bin | head -c 10)")
+
+      with subtest("sharded storage"):
+          sha = client.succeed("sha256sum testfile.bin | cut -d' ' -f1").strip()
+          server.succeed(f"test -f /var/lib/urlnao/uploads/{sha[:2]}/{sha[2:4]}/{sha}")
+          server.fail(f"test -e /var/lib/urlnao/uploads/{sha}")
+
+      with subtest("duplicate uploads"):
+          client.succeed(
+              "curl -sSf -H 'Accept: application/json' -H 'X-Forwarded-For: 203.0.113.7'"
+              " -H 'X-Remote-User: mallory'"
+              " -F 'file=@testfile.bin;filename=copy.bin' http://server/up > copy.json"
+          )
+          # only the address added by the proxy is recorded
+          client.succeed("grep -q '\"forwarded_for\": \"[0-9]' copy.json")
+          client.fail("grep -q '203.0.113.7' copy.json")
+          client.succeed("grep -q '\"remote_user\": null' copy.json")
+          client.succeed("grep -q '\"filename\": \"copy.bin\"' copy.json")
+          client.fail('grep -qF "\\"$(cat url)\\"" copy.json')
+          client.succeed(
+              "sed -n 's/.*\"deletion_url\": \"\\(.*\\)\".*/\\1/p' copy.json | xargs curl -sSf"
+          )
+          client.succeed(
+              "xargs <url curl -sS -o /dev/null -w '%{redirect_url}' | grep -q '/testfile.bin$'"
+          )
+          client.succeed("xargs <url curl -L -sSf --output download.bin")
+          client.succeed("cmp testfile.bin download.bin")
+
+      with subtest("uploader on tcp listeners"):
+          server.succeed("echo tcp > tcp.bin")
+          server.succeed(
+              "${pkgs.curl}/bin/curl -sSf -H 'Accept: application/json'"
+              " -H 'X-Forwarded-For: 203.0.113.7' -H 'X-Remote-User: mallory'"
+              " -F file=@tcp.bin http://127.0.0.1:8080/up > tcp.json"
+          )
+          server.succeed("grep -q '\"forwarded_for\": \"127.0.0.1\"' tcp.json")
+          server.succeed("grep -q '\"remote_user\": null' tcp.json")
+
+      with subtest("content types"):
+          client.succeed("printf '\\x89PNG\\r\\n\\x1a\\n' > picture")
+          client.succeed("curl -sSf -F file=@picture http://server/up > picture_url")
+          client.succeed(
+              "xargs <picture_url curl -L -sSf -D - -o /dev/null"
+              " | grep -qi 'content-type: image/png'"
+          )
+          client.succeed('printf \'<svg xmlns="http://www.w3.org/2000/svg"/>\' > drawing.svg')
+          client.succeed("curl -sSf -F file=@drawing.svg http://server/up > drawing_url")
+          client.succeed(
+              "xargs <drawing_url curl -L -sSf -D - -o /dev/null"
+              " | grep -qi 'content-disposition: attachment'"
+          )
+
+      with subtest("state"):
+          client.succeed(
+              "curl -sSf 'http://server/state?format=json&name=testfile&limit=1'"
+              " | grep -q '\"filename\": \"testfile.bin\"'"
+          )
+          client.succeed(
+              "curl -sSf 'http://server/state?format=json&name=testfile'"
+              " | grep -q '\"forwarded_for\": \"[0-9]'"
+          )
+          client.succeed(
+              '[ "400" -eq "$(curl -so /dev/null -w "%{http_code}" "http://server/state?limit=0")" ]'
+          )
+
+      with subtest("request ids"):
+          client.succeed(
+              "curl -sSf -D - -o /dev/null -H 'X-Request-Id: test-request-1' http://server/"
+              " | grep -qi 'x-request-id: test-request-1'"
+          )
+          server.succeed("journalctl -u urlnao | grep -q '\"GET / HTTP/1.1\" 200 .* test-request-1 '")
+          # the address a client puts in X-Forwarded-For itself is not logged
+          client.succeed(
+              "curl -sSf -o /dev/null -H 'X-Request-Id: test-request-2'"
+              " -H 'X-Forwarded-For: 198.51.100.1' http://server/"
+          )
+          server.succeed("journalctl -u urlnao | grep -q ' test-request-2 '")
+          server.fail("journalctl -u urlnao | grep -q 'access: 198.51.100.1 .* test-request-2 '")
+
+      with subtest("metrics"):
+          server.succeed(
+              "${pkgs.curl}/bin/curl -sSf http://127.0.0.1:9100/metrics"
+              " | grep -q '^urlnao_uploads_total [1-9]'"
+          )
+          client.fail("curl -sSf http://server/metrics")
+
+      with subtest("hostile filenames"):
+          client.succeed("head -c 235 /dev/urandom > script.bin")
+          client.succeed(
+              "curl -sSf -F 'file=@script.bin;filename=<script>alert(1)</script>.png' http://server/up"
+          )
+          client.succeed("head -c 235 /dev/urandom > img.bin")
+          client.succeed(
+              "curl -sSf -F 'file=@img.bin;filename=\"><img src=x onerror=alert(1)>.txt' http://server/up"
+          )
+          client.succeed("curl -sSf -D state_headers 'http://server/state?limit=1000' > state.html")
+          client.fail("grep -q '<script' state.html")
+          client.fail("grep -q '<img' state.html")
+          client.succeed("grep -qF '&lt;script&gt;alert(1)&lt;/script&gt;.png' state.html")
+          client.succeed("grep -qi \"content-security-policy: default-src 'none'\" state_headers")
+          client.succeed(
+              "curl -s -D - 'http://server/state?sort=%3Cscript%3E' | grep -qi 'content-type: text/plain'"
+          )
+
+      with subtest("filename encoding"):
+          client.succeed("head -c 235 /dev/urandom > report.bin")
+          client.succeed(
+              "curl -sSf -F 'file=@report.bin;filename=\"../Grüße; 2024.pdf\"' http://server/up > report_url"
+          )
+          client.succeed(
+              "xargs <report_url curl -L -sSf -D - -o /dev/null"
+              " | grep -qiF 'content-disposition: attachment; filename=\"Gr__e; 2024.pdf\";"
+              " filename*=UTF-8'\\'''\\''Gr%C3%BC%C3%9Fe%3B%202024.pdf'"
+          )
+
+      with subtest("deletion"):
+          client.succeed("head -c 235 /dev/urandom > deleted.bin")
+          client.succeed(
+              "curl -sSf -D headers -F file=@deleted.bin http://server/up > url"
+          )
+          client.succeed(
+              "grep -i x-urlnao-deletion-url headers | cut -d' ' -f2 | tr -d '\\r' > delete_url"
+          )
+          client.succeed("xargs <url curl -sS -o /dev/null -w '%{redirect_url}' > download_url")
+          client.succeed("xargs <delete_url curl -sSf")
+          client.succeed(
+              '[ "410" -eq "$(xargs <url curl -so /dev/null -w "%{http_code}")" ]'
+          )
+          client.succeed(
+              '[ "410" -eq "$(xargs <download_url curl -so /dev/null -w "%{http_code}")" ]'
+          )
+
+      with subtest("expiry"):
+          client.succeed("head -c 235 /dev/urandom > expiring.bin")
+          client.succeed("curl -sSf -F ttl=1s -F file=@expiring.bin http://server/up > url")
+          client.sleep(2)
+          client.succeed(
+              '[ "410" -eq "$(xargs <url curl -so /dev/null -w "%{http_code}")" ]'
+          )
+
+          # clients cannot keep uploads longer than --default-ttl
+          client.succeed("head -c 235 /dev/urandom > forever.bin")
+          for ttl in ["-F ttl=never", "-F ttl=0", "-H 'X-Urlnao-TTL: never'"]:
+              client.succeed(
+                  f"curl -sSf {ttl} -F file=@forever.bin 'http://server/up?format=json'"
+                  " | grep -q '\"expires_at\": [0-9]'"
+              )
+
+      with subtest("upload limits"):
+          client.succeed("head -c 70000 /dev/urandom > big.bin")
+          client.succeed(
+              '[ "413" -eq "$(curl -s -o response -w "%{http_code}" -F file=@big.bin http://server/up)" ]'
+          )
+          client.succeed("grep -q 'files are limited to 64000 bytes' response")
+
+          # the proxy buffers request bodies, chunked ones are sent to the listener directly
+          server.succeed("head -c 60000 /dev/urandom > part.bin")
+          server.succeed(
+              '[ "413" -eq "$(${pkgs.curl}/bin/curl -s -o response -w "%{http_code}"'
+              " -H 'Transfer-Encoding: chunked' -F a=@part.bin -F b=@part.bin"
+              ' http://127.0.0.1:8080/up)" ]'
+          )
+          server.succeed("grep -q 'uploads are limited to 96000 bytes' response")
+
+          client.succeed("echo small > small.bin")
+          client.succeed(
+              '[ "413" -eq "$(curl -s -o response -w "%{http_code}"'
+              ' -F a=@small.bin -F b=@small.bin -F c=@small.bin http://server/up)" ]'
+          )
+          client.succeed("grep -q 'uploads are limited to 2 file(s)' response")
+
+          client.succeed("touch empty.bin")
+          client.succeed(
+              '[ "422" -eq "$(curl -s -o response -w "%{http_code}" -F file=@empty.bin http://server/up)" ]'
+          )
+          client.succeed("grep -q 'file is empty' response")
+
+      with subtest("api keys"):
+          keyed.wait_for_unit("urlnao.service")
+          keyed.wait_for_unit("nginx.service")
+          # keys are added while the database is not in use
+          keyed.succeed("systemctl stop urlnao.service")
+          add_key = "cd /var/lib/urlnao && runuser -u urlnao -- ${pkgs.urlnao}/bin/urlnao key add"
+          alice = keyed.succeed(f"{add_key} alice").strip()
+          bob = keyed.succeed(f"{add_key} bob").strip()
+          uploader = keyed.succeed(f"{add_key} uploader --scopes upload").strip()
+          keyed.succeed("systemctl start urlnao.service")
+          keyed.wait_for_unit("urlnao.service")
+
+          client.succeed("head -c 235 /dev/urandom > keyfile.bin")
+          client.succeed(
+              '[ "401" -eq "$(curl -s -D unauthorized_headers -o /dev/null -w "%{http_code}"'
+              ' -F file=@keyfile.bin http://keyed/up)" ]'
+          )
+          client.succeed("grep -qi 'www-authenticate: Bearer' unauthorized_headers")
+
+          client.succeed(
+              f"curl -sSf -H 'Authorization: Bearer {alice}' -F file=@keyfile.bin http://keyed/up"
+              " > alice_url"
+          )
+          # the same content uploaded with another key is an upload of its own
+          client.succeed(f"curl -sSf -u 'bob:{bob}' -F file=@keyfile.bin http://keyed/up > bob_url")
+
+          client.succeed(
+              f"curl -sSf -H 'Authorization: Bearer {alice}' 'http://keyed/state?format=json'"
+              " > alice_state"
+          )
+          client.succeed('grep -qF "\\"$(cat alice_url)\\"" alice_state')
+          client.fail('grep -qF "\\"$(cat bob_url)\\"" alice_state')
+          client.succeed(
+              '[ "403" -eq "$(curl -so /dev/null -w "%{http_code}"'
+              f" -H 'Authorization: Bearer {uploader}' http://keyed/state)\" ]"
+          )
+
+          client.succeed(
+              '[ "403" -eq "$(xargs <alice_url curl -so /dev/null -w "%{http_code}" -X DELETE'
+              f" -u 'bob:{bob}')\" ]"
+          )
+          client.succeed(f"xargs <bob_url curl -sSf -X DELETE -u 'bob:{bob}'")
+          client.succeed("xargs <alice_url curl -L -sSf --output keyed_download.bin")
+          client.succeed("cmp keyfile.bin keyed_download.bin")
+
+      with subtest("api key file"):
+          import hashlib
+          digest = hashlib.sha256(b"ci-key-1").hexdigest()
+          upload_ci = (
+              'curl -so /dev/null -w "%{http_code}" -H "Authorization: Bearer ci-key-1"'
+              " -F file=@keyfile.bin http://keyed/up"
+          )
+          client.succeed(f'[ "401" -eq "$({upload_ci})" ]')
+
+          # keys are added and revoked without a restart
+          keyed.succeed(
+              f"printf '[[key]]\\nname = \"ci\"\\nsha256 = \"{digest}\"\\n'"
+              " > /var/lib/urlnao/keys.toml"
+          )
+          keyed.succeed("systemctl reload urlnao.service")
+          client.wait_until_succeeds(f'[ "200" -eq "$({upload_ci})" ]')
+
+          keyed.succeed("truncate -s 0 /var/lib/urlnao/keys.toml")
+          keyed.succeed("systemctl reload urlnao.service")
+          client.wait_until_succeeds(f'[ "401" -eq "$({upload_ci})" ]')
+          keyed.succeed("systemctl is-active urlnao.service")
     '';
   })
@@ -0,0 +1,223 @@
+use crate::auth::{ApiKey, Scope};
+use crate::db::{self, ScanOrder};
+use crate::file::{self, Storage};
+use crate::gc;
+use crate::reaper;
+use crate::util;
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Maintenance tasks that operate on the database and `uploads/` directly,
+/// while the server is stopped.
+#[derive(Clone)]
+pub enum Command {
+    List,
+    Show(String),
+    Delete(String),
+    Rename(String, String),
+    Gc { report_only: bool, rehash: bool },
+    Stats,
+    Verify,
+    KeyAdd(String, Vec<Scope>),
+    KeyRevoke(String),
+    KeyList,
+}
+
+pub async fn run(command: &Command, storage: &Storage, db: sled::Db) -> Result<(), String> {
+    let result = match command {
+        Command::List => list(db.clone()),
+        Command::Show(id) => show(db.clone(), storage, id).await,
+        Command::Delete(id) => delete(db.clone(), storage, id).await,
+        Command::Rename(id, name) => rename(db.clone(), id, name).await,
+        Command::Gc { report_only, rehash } => gc(db.clone(), storage, *report_only, *rehash).await,
+        Command::Stats => stats(db.clone(), storage).await,
+        Command::Verify => verify(db.clone(), storage),
+        Command::KeyAdd(name, scopes) => add_key(db.clone(), name, scopes).await,
+        Command::KeyRevoke(name) => revoke_key(db.clone(), name).await,
+        Command::KeyList => list_keys(db.clone()).await,
+    };
+
+    db.flush_async().await.map_err(to_string)?;
+
+    result
+}
+
+fn list(db: sled::Db) -> Result<(), String> {
+    let mut out = std::io::stdout().lock();
+
+    for upload in db::scan_uploads(&db, ScanOrder::Id, false, None).map_err(to_string)? {
+        let upload = upload.map_err(to_string)?;
+        let metadata = &upload.metadata;
+        let written = writeln!(out, "{}\t{}\t{}\t{}\t{}",
+            upload.id,
+            or_dash(metadata.size),
+            or_dash(metadata.uploaded_at),
+            metadata.mime_type,
+            metadata.filename.as_deref().unwrap_or("-"));
+        // the listing is likely piped into something like head
+        match written {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            written => written.map_err(to_string)?,
+        }
+    }
+    Ok(())
+}
+
+async fn show(db: sled::Db, storage: &Storage, id: &str) -> Result<(), String> {
+    let (sha256, metadata) = db::try_get_sha_and_metadata(db.clone(), id.as_bytes()).await
+        .map_err(|e| format!("upload {}: {}", id, e))?;
+    let expiry = db::try_get_expiry(db, id.as_bytes()).await.map_err(to_string)?;
+
+    println!("id:            {}", id);
+    println!("sha256:        {}", sha256);
+    println!("filename:      {}", metadata.filename.as_deref().unwrap_or("-"));
+    println!("field:         {}", metadata.field_name.as_deref().unwrap_or("-"));
+    println!("size:          {}", or_dash(metadata.size));
+    println!("mime type:     {}", metadata.mime_type);
+    println!("uploaded at:   {}", or_dash(metadata.uploaded_at));
+    println!("expires at:    {}", expiry.map_or("never".to_owned(), |e| e.to_string()));
+    println!("forwarded for: {}", metadata.uploader.forwarded_for.as_deref().unwrap_or("-"));
+    println!("remote user:   {}", metadata.uploader.remote_user.as_deref().unwrap_or("-"));
+    println!("api key:       {}", metadata.uploader.api_key.as_deref().unwrap_or("-"));
+    println!("blob:          {}", storage.blob_path(&sha256));
+    Ok(())
+}
+
+async fn delete(db: sled::Db, storage: &Storage, id: &str) -> Result<(), String> {
+    match db::try_delete(db.clone(), id.as_bytes(), db::Proof::Admin).await.map_err(to_string)? {
+        db::Deletion::Deleted(unreferenced) => {
+            if let Some(sha256) = unreferenced {
+                file::try_remove_from_uploads(&sha256, storage, &db).await.map_err(to_string)?;
+            }
+            println!("deleted {}", id);
+            Ok(())
+        },
+        db::Deletion::UnknownId | db::Deletion::Denied => Err(format!("there is no upload {}", id)),
+    }
+}
+
+async fn rename(db: sled::Db, id: &str, name: &str) -> Result<(), String> {
+    let name = util::sanitize_filename(name);
+    match db::try_rename(db, id.as_bytes(), &name).await.map_err(to_string)? {
+        true => {
+            println!("renamed {} to {}", id, name);
+            Ok(())
+        },
+        false => Err(format!("there is no upload {}", id)),
+    }
+}
+
+async fn gc(db: sled::Db, storage: &Storage, report_only: bool, rehash: bool) -> Result<(), String> {
+    if !report_only {
+        reaper::reap_expired(db.clone(), storage).await;
+    }
+
+    // nothing is in progress while the server is stopped
+    let options = gc::Options { report_only, rehash, grace_period: Duration::from_secs(0) };
+    gc::run(db, storage, &options).await.map_err(to_string)?.log(report_only);
+    Ok(())
+}
+
+async fn stats(db: sled::Db, storage: &Storage) -> Result<(), String> {
+    let (uploads, bytes) = db::get_totals(db.clone(), None).await.map_err(to_string)?;
+
+    let (blobs, blob_bytes) = file::upload_dir_usage(storage).await.map_err(to_string)?;
+
+    println!("uploads:       {}", uploads);
+    println!("upload bytes:  {}", bytes);
+    println!("blobs:         {}", blobs);
+    println!("blob bytes:    {}", blob_bytes);
+    println!("database size: {}", db.size_on_disk().map_err(to_string)?);
+
+    let mut names = db.tree_names();
+    names.sort();
+
+    for name in names {
+        let tree = db.open_tree(&name).map_err(to_string)?;
+        println!("tree {}: {} entries", String::from_utf8_lossy(&name), tree.len());
+    }
+    Ok(())
+}
+
+/// Checks that every upload has a blob of the recorded size.
+fn verify(db: sled::Db, storage: &Storage) -> Result<(), String> {
+    let mut problems = 0;
+
+    for upload in db::scan_uploads(&db, ScanOrder::Id, false, None).map_err(to_string)? {
+        let upload = match upload {
+            Ok(upload) => upload,
+            Err(e) => {
+                println!("{}", e);
+                problems += 1;
+                continue
+            },
+        };
+
+        let path = storage.blob_path(&upload.checksum);
+        match (std::fs::metadata(&path), upload.metadata.size) {
+            (Err(e), _) => {
+                println!("{}: blob {} is not readable: {}", upload.id, path, e);
+                problems += 1;
+            },
+            (Ok(blob), Some(size)) if blob.len() != size => {
+                println!("{}: blob {} has {} bytes, expected {}", upload.id, path, blob.len(), size);
+                problems += 1;
+            },
+            _ => (),
+        }
+    }
+
+    match problems {
+        0 => {
+            println!("no problems found");
+            Ok(())
+        },
+        n => Err(format!("{} problem(s) found", n)),
+    }
+}
+
+async fn add_key(db: sled::Db, name: &str, scopes: &[Scope]) -> Result<(), String> {
+    let key = util::new_api_key();
+    let api_key = ApiKey {
+        name: name.to_owned(),
+        scopes: scopes.to_vec(),
+        created_at: util::unix_now(),
+    };
+
+    let added = db::try_add_api_key(db, &util::hash_token(&key), &api_key).await
+        .map_err(to_string)?;
+    if !added {
+        return Err(format!("an API key named '{}' already exists", name));
+    }
+
+    // the key is not stored, this is the only chance to see it
+    println!("{}", key);
+    Ok(())
+}
+
+async fn revoke_key(db: sled::Db, name: &str) -> Result<(), String> {
+    match db::try_revoke_api_key(db, name).await.map_err(to_string)? {
+        true => {
+            println!("revoked API key '{}'", name);
+            Ok(())
+        },
+        false => Err(format!("there is no API key named '{}'", name)),
+    }
+}
+
+async fn list_keys(db: sled::Db) -> Result<(), String> {
+    for key in db::get_api_keys(db).await.map_err(to_string)? {
+        let scopes: Vec<String> = key.scopes.iter().map(Scope::to_string).collect();
+        println!("{}\t{}\t{}", key.name, scopes.join(","), key.created_at);
+    }
+    Ok(())
+}
+
+fn or_dash(value: Option<u64>) -> String {
+    value.map_or("-".to_owned(), |v| v.to_string())
+}
+
+fn to_string(e: impl std::fmt::Display) -> String {
+    e.to_string()
+}
@@ -0,0 +1,232 @@
+use crate::config::Config;
+use crate::db;
+use crate::error::Error;
+use crate::file;
+use crate::util;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// What an API key may be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Upload,
+    /// list the key's own uploads
+    List,
+    /// delete the key's own uploads without their deletion token
+    Delete,
+    /// all of the above, for the uploads of every key and anonymous ones
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Result<Scope, String> {
+        match s.trim() {
+            "upload" => Ok(Scope::Upload),
+            "list"   => Ok(Scope::List),
+            "delete" => Ok(Scope::Delete),
+            "admin"  => Ok(Scope::Admin),
+            s => Err(format!("unknown scope '{}', expected upload, list, delete or admin", s)),
+        }
+    }
+
+    pub fn parse_list(s: &str) -> Result<Vec<Scope>, String> {
+        s.split(',').filter(|s| !s.trim().is_empty()).map(Scope::parse).collect()
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Upload => "upload",
+            Scope::List   => "list",
+            Scope::Delete => "delete",
+            Scope::Admin  => "admin",
+        })
+    }
+}
+
+/// An API key as stored in `api_keys`, keyed by the SHA-256 digest of the key.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// identifies the key in metadata, unique among all keys
+    pub name:       String,
+    pub scopes:     Vec<Scope>,
+    /// 0 for keys from the key file
+    pub created_at: u64,
+}
+
+/// API keys read from `--api-key-file`, by the hex encoded SHA-256 digest of the key.
+pub type FileKeys = Arc<[(String, ApiKey)]>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    #[serde(default)]
+    key: Vec<KeyFileEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFileEntry {
+    name:   String,
+    sha256: String,
+    #[serde(default = "default_scopes")]
+    scopes: Vec<Scope>,
+}
+
+fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Upload, Scope::List, Scope::Delete]
+}
+
+/// Reads API keys from a TOML file with a `[[key]]` table per key, which
+/// unlike the keys in the database can be changed while the server runs.
+pub fn read_key_file(path: &str) -> Result<FileKeys, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read API key file {}: {}", path, e))?;
+
+    let file: KeyFile = toml::from_str(&content)
+        .map_err(|e| format!("failed to parse API key file {}: {}", path, e))?;
+
+    let mut names = HashSet::new();
+    let mut keys = vec![];
+
+    for entry in file.key {
+        if !names.insert(entry.name.clone()) {
+            return Err(format!("API key '{}' is listed twice in {}", entry.name, path));
+        }
+        if !file::is_checksum(&entry.sha256) {
+            return Err(format!("invalid sha256 of API key '{}' in {}, expected 64 hex digits",
+                entry.name, path));
+        }
+        keys.push((entry.sha256.to_lowercase(),
+            ApiKey { name: entry.name, scopes: entry.scopes, created_at: 0 }));
+    }
+
+    Ok(Arc::from(keys))
+}
+
+impl ApiKey {
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// The client a request was made by.
+pub enum Access {
+    /// API keys are not required
+    Anonymous,
+    Key(ApiKey),
+}
+
+impl Access {
+    /// The name of the key whose own uploads are the only ones visible,
+    /// `None` if all uploads are.
+    pub fn owner(&self) -> Option<&str> {
+        match self {
+            Access::Key(key) if !key.scopes.contains(&Scope::Admin) => Some(&key.name),
+            _ => None,
+        }
+    }
+
+    pub fn key_name(&self) -> Option<&str> {
+        match self {
+            Access::Key(key) => Some(&key.name),
+            Access::Anonymous => None,
+        }
+    }
+}
+
+/// Checks the credentials of a request for the given scope. Unless API keys
+/// are required, credentials are ignored, since a proxy doing basic auth
+/// forwards its own `Authorization` header.
+pub async fn authorize(
+    authorization: Option<&str>,
+    scope: Scope,
+    config: &Config,
+    db: &sled::Db,
+) -> Result<Access, Error> {
+    if !config.require_api_key {
+        return Ok(Access::Anonymous);
+    }
+
+    let key = authorization.and_then(parse_authorization).ok_or(Error::Unauthorized)?;
+
+    let key_hash = util::hash_token(&key);
+
+    let key = match db::get_api_key(db.clone(), &key_hash).await? {
+        Some(key) => key,
+        None => {
+            let digest: String = key_hash.iter().map(|b| format!("{:02x}", b)).collect();
+            config.file_keys.iter()
+                .find(|(sha256, _)| *sha256 == digest)
+                .map(|(_, key)| key.clone())
+                .ok_or(Error::Unauthorized)?
+        },
+    };
+
+    match key.allows(scope) {
+        true => Ok(Access::Key(key)),
+        false => Err(Error::Forbidden(scope)),
+    }
+}
+
+/// Takes the key from `Bearer <key>` or from the password of basic auth,
+/// falling back to the user name for clients that only send one of them.
+fn parse_authorization(header: &str) -> Option<String> {
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(credentials.to_owned());
+    }
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+        return match decoded.split_once(':') {
+            Some((user, "")) => Some(user.to_owned()),
+            Some((_, password)) => Some(password.to_owned()),
+            None => Some(decoded),
+        };
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    #[test]
+    fn parses_bearer_keys() {
+        assert_eq!(parse_authorization("Bearer secret"), Some("secret".to_owned()));
+        assert_eq!(parse_authorization("bearer  secret "), Some("secret".to_owned()));
+        assert_eq!(parse_authorization("BEARER secret"), Some("secret".to_owned()));
+    }
+
+    #[test]
+    fn parses_basic_credentials() {
+        assert_eq!(parse_authorization(&basic("user:secret")), Some("secret".to_owned()));
+        assert_eq!(parse_authorization(&basic("user:sec:ret")), Some("sec:ret".to_owned()));
+        assert_eq!(parse_authorization(&basic(":secret")), Some("secret".to_owned()));
+        assert_eq!(parse_authorization(&basic("secret:")), Some("secret".to_owned()));
+        assert_eq!(parse_authorization(&basic("secret")), Some("secret".to_owned()));
+    }
+
+    #[test]
+    fn rejects_other_headers() {
+        assert_eq!(parse_authorization("Basic not-base64!"), None);
+        assert_eq!(parse_authorization(&format!("Basic {}", base64::encode([0xff, 0xfe]))), None);
+        assert_eq!(parse_authorization("Digest username=\"user\""), None);
+        assert_eq!(parse_authorization("secret"), None);
+        assert_eq!(parse_authorization(""), None);
+    }
+}
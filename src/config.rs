@@ -1,45 +1,178 @@
-use clap::{Arg, App};
+use crate::admin::Command;
+use crate::auth::{self, FileKeys, Scope};
+use crate::file::Storage;
+use crate::listen::{self, ListenAddr};
+use crate::logging::{self, LogFormat};
+use crate::sniff;
+use crate::util;
+
+use arc_swap::ArcSwap;
+use clap::{App, AppSettings, Arg, SubCommand};
+use tracing::info;
+use tracing::level_filters::LevelFilter;
 use std::sync::Arc;
 
 pub enum SuffixType {
     ShortID,
     FileName,
+    Deletion,
 }
 
 #[derive(Clone)]
 pub struct Config {
-    pub socket_path: Arc<str>,
+    pub listen:      Arc<[ListenAddr]>,
+    pub socket_mode:  u32,
+    pub socket_owner: Option<u32>,
+    pub socket_group: Option<u32>,
+    /// where `/metrics` is served, apart from the public listeners
+    pub metrics_listen: Option<ListenAddr>,
     pub db_path:     Arc<str>,
-    protocol:        Arc<str>,
-    hostname:        Arc<str>,
-    port:            Arc<str>,
-    shortid_path:    Arc<str>,
-    download_path:   Arc<str>,
+    pub storage:     Storage,
+    pub default_ttl: Option<u64>,
+    /// the longest time to live clients may choose, `default_ttl` unless given,
+    /// `None` lets uploads be kept forever
+    pub max_ttl:     Option<u64>,
+    pub max_upload_size: u64,
+    pub max_file_size:   u64,
+    pub max_files:       usize,
+    pub base_path:     Arc<str>,
+    pub shortid_path:  Arc<str>,
+    pub download_path: Arc<str>,
+    pub dry_run_migrations: bool,
+    pub inline_types:     Arc<[String]>,
+    pub attachment_types: Arc<[String]>,
+    pub require_api_key:  bool,
+    /// keys from `--api-key-file`, in addition to those in the database
+    pub file_keys:     FileKeys,
+    pub gc_interval:   Option<u64>,
+    /// how long to wait for requests in progress on shutdown, `None` waits indefinitely
+    pub drain_timeout: Option<u64>,
+    pub log_level:     LevelFilter,
+    pub log_format:    LogFormat,
+    /// a maintenance task to run instead of the server
+    pub command:       Option<Command>,
+    /// validate and print the configuration instead of serving
+    pub check_config:  bool,
+    pub config_path:   Option<Arc<str>>,
+    /// the effective value of each setting, for printing the configuration
+    settings:          Arc<[(&'static str, String)]>,
+    protocol:          Arc<str>,
+    hostname:          Arc<str>,
+    port:              Option<u16>,
 }
 
+/// Settings that can also be given in the config file and as `URLNAO_*`
+/// environment variables, named like their command-line arguments.
+const SETTINGS: &[&str] = &[
+    "socket_path", "listen", "socket_mode", "socket_owner", "socket_group", "metrics_listen",
+    "db_path", "data_dir", "tmp_dir",
+    "hostname", "port", "protocol", "shortid_path", "download_path", "base_path",
+    "default_ttl", "max_ttl", "max_upload_size", "max_file_size", "max_files",
+    "inline_types", "attachment_types", "gc_interval", "drain_timeout", "require_api_key",
+    "api_key_file", "log_level", "log_format",
+];
+
+/// Settings that are in use from startup on and require a restart to change.
+const NOT_RELOADABLE: &[&str] = &[
+    "socket_path", "listen", "socket_mode", "socket_owner", "socket_group", "metrics_listen",
+    "db_path", "data_dir", "tmp_dir",
+    "shortid_path", "download_path", "base_path", "gc_interval",
+    "log_level", "log_format",
+];
+
+/// The configuration in effect, replaced as a whole on reload.
+pub type LiveConfig = Arc<ArcSwap<Config>>;
+
+/// Settings without a value, enabled by their mere presence.
+const FLAGS: &[&str] = &["require_api_key"];
+
+// paths of endpoints that are not configurable
+const FIXED_PATHS: &[&str] = &["up", "state", "del"];
+
 impl std::fmt::Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{{ socket_path: '{}', protocol: '{}', hostname: '{}', port: '{}' }}",
-            self.socket_path, self.protocol, self.hostname, self.port)
+        let listen: Vec<String> = self.listen.iter().map(ListenAddr::to_string).collect();
+        write!(f, "{{ listen: '{}', protocol: '{}', hostname: '{}', port: '{}' }}",
+            listen.join(","), self.protocol, self.hostname,
+            self.port.map_or(String::new(), |port| port.to_string()))
     }
 }
 
 impl Config {
-    pub fn init() -> Self {
+    /// Reads the configuration from the command line, `URLNAO_*` environment
+    /// variables and the config file, in this order of precedence.
+    pub fn init() -> Result<Self, String> {
         let matches = App::new("urlnao")
             .version("0.2.0")
             .about("Upload service for file sharing with weechat-android")
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help("TOML file with settings named like the\n\
+                       arguments, e.g. max_upload_size = \"1G\"")
+                .global(true))
+            .arg(Arg::with_name("check_config")
+                .long("check-config")
+                .help("Validate and print the effective\n\
+                       configuration and exit"))
             .arg(Arg::with_name("socket_path")
                 .short("s")
                 .long("socket-path")
                 .takes_value(true)
-                .help("Path to the Unix domain socket")
+                .help("Path to the Unix domain socket,\n\
+                       unless --listen is given")
                 .default_value("./urlnao.sock"))
+            .arg(Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| ListenAddr::parse(&v).map(|_| ()))
+                .help("Address to accept connections on, e.g.\n\
+                       tcp://127.0.0.1:8080 or unix:/run/urlnao.sock,\n\
+                       may be given multiple times"))
+            .arg(Arg::with_name("socket_mode")
+                .long("socket-mode")
+                .takes_value(true)
+                .validator(|v| listen::parse_mode(&v).map(|_| ()))
+                .help("Permissions of Unix domain sockets")
+                .default_value("660"))
+            .arg(Arg::with_name("socket_owner")
+                .long("socket-owner")
+                .takes_value(true)
+                .validator(|v| listen::parse_user(&v).map(|_| ()))
+                .help("User name or id owning Unix domain sockets"))
+            .arg(Arg::with_name("socket_group")
+                .long("socket-group")
+                .takes_value(true)
+                .validator(|v| listen::parse_group(&v).map(|_| ()))
+                .help("Group name or id owning Unix domain sockets"))
+            .arg(Arg::with_name("metrics_listen")
+                .long("metrics-listen")
+                .takes_value(true)
+                .validator(|v| ListenAddr::parse(&v).map(|_| ()))
+                .help("Address to serve Prometheus metrics on,\n\
+                       e.g. tcp://127.0.0.1:9100, disabled by default"))
             .arg(Arg::with_name("db_path")
                 .long("db-path")
                 .takes_value(true)
                 .help("Path to urlnao's key-value store")
+                .global(true)
                 .default_value("./db"))
+            .arg(Arg::with_name("data_dir")
+                .long("data-dir")
+                .takes_value(true)
+                .help("Directory in which uploads are stored")
+                .global(true)
+                .default_value("."))
+            .arg(Arg::with_name("tmp_dir")
+                .long("tmp-dir")
+                .takes_value(true)
+                .help("Directory for uploads in progress,\n\
+                       tmp/ in the data directory by default")
+                .global(true))
             .arg(Arg::with_name("hostname")
                 .short("h")
                 .long("hostname")
@@ -50,6 +183,7 @@ impl Config {
                 .long("port")
                 .takes_value(true)
                 .help("Optional non-standard port under\nwhich urlnao is reachable")
+                .validator(|v| parse_port(&v).map(|_| ()))
                 .default_value(""))
             .arg(Arg::with_name("protocol")
                 .long("protocol")
@@ -67,43 +201,608 @@ impl Config {
                 .takes_value(true)
                 .help("URL path under which files should\nbe reachable by their original name")
                 .default_value("d"))
+            .arg(Arg::with_name("base_path")
+                .long("base-path")
+                .takes_value(true)
+                .help("Optional URL path prefix for all\n\
+                       endpoints, e.g. when running behind\n\
+                       a proxy sub-location")
+                .default_value(""))
+            .arg(Arg::with_name("default_ttl")
+                .long("default-ttl")
+                .takes_value(true)
+                .validator(|v| util::parse_ttl(&v).map(|_| ()))
+                .help("Time after which uploads expire unless\n\
+                       the client requests otherwise,\n\
+                       e.g. 30d, 12h, 15m or never")
+                .default_value("never"))
+            .arg(Arg::with_name("max_ttl")
+                .long("max-ttl")
+                .takes_value(true)
+                .validator(|v| util::parse_ttl(&v).map(|_| ()))
+                .help("Longest time to live clients may choose\n\
+                       for their uploads, e.g. 30d or never,\n\
+                       --default-ttl unless given"))
+            .arg(Arg::with_name("max_upload_size")
+                .long("max-upload-size")
+                .takes_value(true)
+                .validator(|v| util::parse_size(&v).map(|_| ()))
+                .help("Maximum size of an upload request,\n\
+                       e.g. 500M, 2G or 64k")
+                .default_value("500M"))
+            .arg(Arg::with_name("max_file_size")
+                .long("max-file-size")
+                .takes_value(true)
+                .validator(|v| util::parse_size(&v).map(|_| ()))
+                .help("Maximum size of a single uploaded file")
+                .default_value("500M"))
+            .arg(Arg::with_name("max_files")
+                .long("max-files")
+                .takes_value(true)
+                .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Maximum number of files per upload request")
+                .default_value("100"))
+            .arg(Arg::with_name("inline_types")
+                .long("inline-types")
+                .takes_value(true)
+                .help("Comma-separated MIME types that browsers\n\
+                       may display inline, e.g. image/*")
+                .default_value("image/*,audio/*,video/*,text/plain"))
+            .arg(Arg::with_name("attachment_types")
+                .long("attachment-types")
+                .takes_value(true)
+                .help("Comma-separated MIME types that are\n\
+                       always downloaded, taking precedence\n\
+                       over --inline-types")
+                .default_value("image/svg+xml"))
+            .arg(Arg::with_name("gc_interval")
+                .long("gc-interval")
+                .takes_value(true)
+                .validator(|v| util::parse_ttl(&v).map(|_| ()))
+                .help("Time between garbage collection runs,\n\
+                       e.g. 1d, 12h or never")
+                .default_value("1d"))
+            .arg(Arg::with_name("drain_timeout")
+                .long("drain-timeout")
+                .takes_value(true)
+                .validator(|v| parse_timeout(&v).map(|_| ()))
+                .help("Time to wait for uploads in progress\n\
+                       on shutdown, e.g. 30s, 5m or never")
+                .default_value("30s"))
+            .arg(Arg::with_name("log_level")
+                .long("log-level")
+                .takes_value(true)
+                .validator(|v| logging::parse_level(&v).map(|_| ()))
+                .help("Least severe messages to log: off,\n\
+                       error, warn, info, debug or trace")
+                .default_value("info"))
+            .arg(Arg::with_name("log_format")
+                .long("log-format")
+                .takes_value(true)
+                .validator(|v| LogFormat::parse(&v).map(|_| ()))
+                .help("Format of log messages, text or json")
+                .default_value("text"))
+            .arg(Arg::with_name("require_api_key")
+                .long("require-api-key")
+                .help("Require an API key for uploads\n\
+                       and the /state page"))
+            .arg(Arg::with_name("api_key_file")
+                .long("api-key-file")
+                .takes_value(true)
+                .help("TOML file with API keys in addition\n\
+                       to those in the database, read again\n\
+                       on SIGHUP"))
+            .arg(Arg::with_name("dry_run_migrations")
+                .long("dry-run-migrations")
+                .help("Print the changes pending database\n\
+                       migrations would make and exit"))
+            .subcommand(SubCommand::with_name("list")
+                .about("List all uploads: short id, size, upload time, MIME type and filename"))
+            .subcommand(SubCommand::with_name("show")
+                .about("Show everything known about an upload")
+                .arg(Arg::with_name("id").required(true)))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete an upload")
+                .arg(Arg::with_name("id").required(true)))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Change the filename of an upload")
+                .arg(Arg::with_name("id").required(true))
+                .arg(Arg::with_name("name").required(true)))
+            .subcommand(SubCommand::with_name("gc")
+                .about("Remove expired uploads, stale temporary files, unreferenced\n\
+                        blobs and dangling database entries")
+                .arg(Arg::with_name("report_only")
+                    .long("report-only")
+                    .help("Only report what would be removed"))
+                .arg(Arg::with_name("rehash")
+                    .long("rehash")
+                    .help("Hash all blobs to detect corruption")))
+            .subcommand(SubCommand::with_name("stats")
+                .about("Show the number and size of uploads and database trees"))
+            .subcommand(SubCommand::with_name("verify")
+                .about("Check that every upload has a blob of the recorded size"))
+            .subcommand(SubCommand::with_name("key")
+                .about("Manage API keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("add")
+                    .about("Create an API key and print it")
+                    .arg(Arg::with_name("name").required(true))
+                    .arg(Arg::with_name("scopes")
+                        .long("scopes")
+                        .takes_value(true)
+                        .validator(|v| Scope::parse_list(&v).map(|_| ()))
+                        .help("Comma-separated scopes of the key:\n\
+                               upload, list, delete or admin")
+                        .default_value("upload,list,delete")))
+                .subcommand(SubCommand::with_name("revoke")
+                    .about("Revoke an API key")
+                    .arg(Arg::with_name("name").required(true)))
+                .subcommand(SubCommand::with_name("list")
+                    .about("List API keys: name, scopes and creation time")))
             .get_matches();
 
         config_to_struct(matches)
     }
 
+    /// Ensures that the configured paths can be routed unambiguously.
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_valid_hostname(&self.hostname) {
+            return Err(format!("invalid hostname '{}', expected a domain name \
+                or an IP address", self.hostname));
+        }
+
+        if let Some(addr) = &self.metrics_listen {
+            if self.listen.contains(addr) {
+                return Err(format!("metrics listen address '{}' is also a listen address, \
+                    metrics must not be served publicly", addr));
+            }
+        }
+
+        let mut paths = vec![
+            ("short id path", &self.shortid_path),
+            ("download path", &self.download_path),
+        ];
+        if !self.base_path.is_empty() {
+            paths.push(("base path", &self.base_path));
+        }
+
+        for (name, path) in paths {
+            if !path.split('/').all(is_valid_segment) {
+                return Err(format!("invalid {} '{}', only unreserved characters \
+                    are allowed in non-empty path segments", name, path));
+            }
+        }
+
+        if paths_collide(&self.shortid_path, &self.download_path) {
+            return Err(format!("short id path '{}' and download path '{}' collide",
+                self.shortid_path, self.download_path));
+        }
+
+        for fixed in FIXED_PATHS {
+            for path in &[&self.shortid_path, &self.download_path] {
+                if paths_collide(path, fixed) {
+                    return Err(format!("path '{}' collides with the /{} endpoint", path, fixed));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn prepend_url(&self, stype: SuffixType, suffix: &str) -> String {
-        match (self.port.len(), stype) {
-            (0, SuffixType::FileName) => format!("{}://{}/{}/{}",
-                self.protocol, self.hostname, self.download_path, suffix),
-            (0, _) => format!("{}://{}/{}/{}",
-                self.protocol, self.hostname, self.shortid_path, suffix),
-            (_, SuffixType::FileName) => format!("{}://{}:{}/{}/{}",
-                self.protocol, self.hostname, self.port, self.download_path, suffix),
-            (_, _) => format!("{}://{}:{}/{}/{}",
-                self.protocol, self.hostname, self.port, self.shortid_path, suffix),
+        let path = match stype {
+            SuffixType::ShortID  => &*self.shortid_path,
+            SuffixType::FileName => &*self.download_path,
+            SuffixType::Deletion => "del",
+        };
+        let base = match self.base_path.len() {
+            0 => String::new(),
+            _ => format!("/{}", self.base_path),
+        };
+        match self.port {
+            None => format!("{}://{}{}/{}/{}",
+                self.protocol, self.hostname, base, path, suffix),
+            Some(port) => format!("{}://{}:{}{}/{}/{}",
+                self.protocol, self.hostname, port, base, path, suffix),
+        }
+    }
+
+    /// Caps a time to live at `max_ttl`, where `None` never expires.
+    pub fn clamp_ttl(&self, ttl: Option<u64>) -> Option<u64> {
+        match (ttl, self.max_ttl) {
+            (ttl, None) => ttl,
+            (None, Some(max_ttl)) => Some(max_ttl),
+            (Some(ttl), Some(max_ttl)) => Some(ttl.min(max_ttl)),
+        }
+    }
+
+    /// Returns whether files of the given type may be displayed inline.
+    pub fn allows_inline(&self, mime_type: &str) -> bool {
+        sniff::matches_any(mime_type, &self.inline_types)
+            && !sniff::matches_any(mime_type, &self.attachment_types)
+    }
+
+    /// Reads the configuration again, for the settings of the config file
+    /// and the API key file to take effect. The command line and environment
+    /// stay those the process was started with. Returns the changed settings,
+    /// refusing changes to settings that cannot be reloaded.
+    pub fn reload(&self) -> Result<(Config, Vec<String>), String> {
+        let config = Config::init()?;
+        config.validate()?;
+
+        let mut changes = vec![];
+        for name in SETTINGS {
+            let (old, new) = (self.setting(name), config.setting(name));
+            if old == new {
+                continue
+            }
+            if NOT_RELOADABLE.contains(name) {
+                return Err(format!("{} cannot be changed without a restart", name));
+            }
+            changes.push(format!("{}: '{}' -> '{}'",
+                name, old.unwrap_or_default(), new.unwrap_or_default()));
         }
+
+        // the key file may have changed while its path stayed the same
+        if self.file_keys != config.file_keys {
+            changes.push(format!("API keys from the key file: {} -> {}",
+                self.file_keys.len(), config.file_keys.len()));
+        }
+
+        Ok((config, changes))
+    }
+
+    fn setting(&self, name: &str) -> Option<&str> {
+        self.settings.iter().find(|(n, _)| *n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the effective settings in the format of the config file.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.settings.iter() {
+            let value = match *name {
+                _ if FLAGS.contains(name) => value.clone(),
+                "listen" => toml::Value::Array(value.split(',')
+                    .map(|v| toml::Value::String(v.to_owned())).collect()).to_string(),
+                _ => toml::Value::String(value.clone()).to_string(),
+            };
+            out.push_str(&format!("{} = {}\n", name, value));
+        }
+        out
     }
 
     pub fn print(&self) {
-        println!("placing socket at: {}", self.socket_path);
-        println!("using key-value store: {}", self.db_path);
-        println!("upload endpoint is /up");
-        println!("generating shareable URLs with format: {}",
+        if let Some(path) = &self.config_path {
+            info!("read settings from: {}", path);
+        }
+        for addr in self.listen.iter() {
+            info!("listening on: {}", addr);
+        }
+        if let Some(addr) = &self.metrics_listen {
+            info!("serving metrics on: {}", addr);
+        }
+        info!("using key-value store: {}", self.db_path);
+        info!("storing uploads in: {}", self.storage.uploads_dir());
+        info!("receiving uploads in: {}", self.storage.tmp_dir());
+        match self.base_path.len() {
+            0 => info!("upload endpoint is /up"),
+            _ => info!("upload endpoint is /{}/up", self.base_path),
+        }
+        match self.clamp_ttl(self.default_ttl) {
+            Some(ttl) => info!("uploads expire by default after {} seconds", ttl),
+            None => info!("uploads do not expire by default"),
+        }
+        if let Some(max_ttl) = self.max_ttl {
+            info!("uploads expire after at most {} seconds", max_ttl);
+        }
+        info!("accepting up to {} file(s) and {} bytes per upload, at most {} bytes per file",
+            self.max_files, self.max_upload_size, self.max_file_size);
+        info!("displaying inline: {}, except: {}",
+            self.inline_types.join(","), self.attachment_types.join(","));
+        match self.gc_interval {
+            Some(interval) => info!("collecting garbage every {} seconds", interval),
+            None => info!("collecting garbage only on startup"),
+        }
+        match self.drain_timeout {
+            Some(timeout) => info!("waiting up to {} seconds for uploads on shutdown", timeout),
+            None => info!("waiting for all uploads on shutdown"),
+        }
+        match self.require_api_key {
+            true => info!("requiring API keys for uploads and listings"),
+            false => info!("not requiring API keys"),
+        }
+        if let Some(path) = self.setting("api_key_file") {
+            info!("read {} API key(s) from: {}", self.file_keys.len(), path);
+        }
+        info!("generating shareable URLs with format: {}",
             self.prepend_url(SuffixType::ShortID, "<short-id>"));
-        println!("generating download URLs with format: {}",
-            self.prepend_url(SuffixType::FileName, "<orignal-filename>"));
+        info!("generating download URLs with format: {}",
+            self.prepend_url(SuffixType::FileName, "<short-id>/<original-filename>"));
+        info!("generating deletion URLs with format: {}",
+            self.prepend_url(SuffixType::Deletion, "<short-id>/<token>"));
+    }
+}
+
+fn config_to_struct(matches: clap::ArgMatches<'_>) -> Result<Config, String> {
+    let config_path = matches.value_of("config").map(str::to_owned)
+        .or_else(|| std::env::var("URLNAO_CONFIG").ok());
+
+    let file = match &config_path {
+        Some(path) => read_config_file(path)?,
+        None => toml::value::Table::new(),
+    };
+
+    let sources = Sources { matches: &matches, file };
+
+    let mut settings = vec![];
+    for name in SETTINGS {
+        if let Some(value) = sources.get(name)? {
+            settings.push((*name, value));
+        }
+    }
+
+    let get = |name| settings.iter().find(|(n, _)| *n == name).map(|(_, v)| v.as_str());
+    // every other setting has a default
+    let value = |name| get(name).unwrap_or("");
+
+    let explicit_socket_path = sources.explicit("socket_path")?;
+
+    // clients may only keep uploads longer than by default if allowed explicitly
+    let default_ttl = util::parse_ttl(value("default_ttl")).map_err(invalid("default_ttl"))?;
+    let max_ttl = match get("max_ttl") {
+        Some(max_ttl) => util::parse_ttl(max_ttl).map_err(invalid("max_ttl"))?,
+        None => default_ttl,
+    };
+
+    Ok(Config {
+        listen:        Arc::from(parse_listen(get("listen"), explicit_socket_path.as_deref(),
+                           value("socket_path"))
+                           .map_err(invalid("listen"))?),
+        socket_mode:   listen::parse_mode(value("socket_mode")).map_err(invalid("socket_mode"))?,
+        socket_owner:  get("socket_owner").map(listen::parse_user).transpose()
+                           .map_err(invalid("socket_owner"))?,
+        socket_group:  get("socket_group").map(listen::parse_group).transpose()
+                           .map_err(invalid("socket_group"))?,
+        metrics_listen: get("metrics_listen").map(ListenAddr::parse).transpose()
+                           .map_err(invalid("metrics_listen"))?,
+        db_path:       Arc::from(value("db_path")),
+        storage:       Storage::new(value("data_dir"), get("tmp_dir")),
+        default_ttl,
+        max_ttl,
+        max_upload_size: util::parse_size(value("max_upload_size"))
+                           .map_err(invalid("max_upload_size"))?,
+        max_file_size: util::parse_size(value("max_file_size")).map_err(invalid("max_file_size"))?,
+        max_files:     value("max_files").parse().map_err(invalid("max_files"))?,
+        hostname:      Arc::from(value("hostname")),
+        port:          parse_port(value("port")).map_err(invalid("port"))?,
+        protocol:      Arc::from(match value("protocol") {
+                           protocol @ ("http" | "https") => protocol,
+                           protocol => return Err(format!("invalid protocol '{}', \
+                               expected http or https", protocol)),
+                       }),
+        base_path:     Arc::from(trim_slashes(value("base_path"))),
+        shortid_path:  Arc::from(trim_slashes(value("shortid_path"))),
+        download_path: Arc::from(trim_slashes(value("download_path"))),
+        dry_run_migrations: matches.is_present("dry_run_migrations"),
+        inline_types:  Arc::from(parse_mime_list(value("inline_types"))),
+        attachment_types: Arc::from(parse_mime_list(value("attachment_types"))),
+        require_api_key: value("require_api_key") == "true",
+        file_keys:     match get("api_key_file") {
+                           Some(path) => auth::read_key_file(path)?,
+                           None => Arc::from(vec![]),
+                       },
+        gc_interval:   util::parse_ttl(value("gc_interval")).map_err(invalid("gc_interval"))?,
+        drain_timeout: parse_timeout(value("drain_timeout")).map_err(invalid("drain_timeout"))?,
+        log_level:     logging::parse_level(value("log_level")).map_err(invalid("log_level"))?,
+        log_format:    LogFormat::parse(value("log_format")).map_err(invalid("log_format"))?,
+        command:       command_from_matches(&matches),
+        check_config:  matches.is_present("check_config"),
+        config_path:   config_path.map(Arc::from),
+        settings:      Arc::from(settings),
+    })
+}
+
+/// Where settings are taken from, by precedence.
+struct Sources<'a, 'b> {
+    matches: &'b clap::ArgMatches<'a>,
+    file:    toml::value::Table,
+}
+
+impl Sources<'_, '_> {
+    /// Returns the value of a setting, falling back to its default.
+    fn get(&self, name: &str) -> Result<Option<String>, String> {
+        if let Some(value) = self.explicit(name)? {
+            return Ok(Some(value));
+        }
+
+        match FLAGS.contains(&name) {
+            true => Ok(Some("false".to_owned())),
+            false => Ok(self.matches.value_of(name).map(str::to_owned)),
+        }
+    }
+
+    /// Returns the value of a setting unless it was not given at all.
+    fn explicit(&self, name: &str) -> Result<Option<String>, String> {
+        let is_flag = FLAGS.contains(&name);
+
+        if self.matches.occurrences_of(name) > 0 {
+            return Ok(match is_flag {
+                true => Some("true".to_owned()),
+                false => self.matches.values_of(name)
+                    .map(|values| values.collect::<Vec<_>>().join(",")),
+            });
+        }
+
+        let env = format!("URLNAO_{}", name.to_uppercase());
+        if let Ok(value) = std::env::var(&env) {
+            return match is_flag {
+                true => parse_bool(&value).map(|b| Some(b.to_string()))
+                    .map_err(|e| format!("{}: {}", env, e)),
+                false => Ok(Some(value)),
+            };
+        }
+
+        match self.file.get(name) {
+            Some(toml::Value::Boolean(b)) if is_flag => Ok(Some(b.to_string())),
+            Some(_) if is_flag => Err(format!("invalid {}: expected true or false", name)),
+            Some(toml::Value::String(s)) => Ok(Some(s.clone())),
+            Some(toml::Value::Integer(i)) => Ok(Some(i.to_string())),
+            Some(toml::Value::Array(values)) => values.iter()
+                .map(|value| match value {
+                    toml::Value::String(s) => Ok(s.clone()),
+                    _ => Err(format!("invalid {}: expected a list of strings", name)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|values| Some(values.join(","))),
+            Some(_) => Err(format!("invalid {}: expected a string", name)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn invalid<E: std::fmt::Display>(name: &'static str) -> impl Fn(E) -> String {
+    move |e| format!("{}: {}", name, e)
+}
+
+fn read_config_file(path: &str) -> Result<toml::value::Table, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config file {}: {}", path, e))?;
+
+    let file: toml::value::Table = toml::from_str(&content)
+        .map_err(|e| format!("failed to parse config file {}: {}", path, e))?;
+
+    // typos would otherwise go unnoticed
+    match file.keys().find(|key| !SETTINGS.contains(&key.as_str())) {
+        Some(key) => Err(format!("unknown setting '{}' in config file {}", key, path)),
+        None => Ok(file),
+    }
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "" | "0" | "false" | "no" | "off" => Ok(false),
+        s => Err(format!("invalid value '{}', expected true or false", s)),
+    }
+}
+
+/// Unlike a ttl, a timeout of 0 does not mean never.
+fn parse_timeout(s: &str) -> Result<Option<u64>, String> {
+    match s.trim() {
+        "never" => Ok(None),
+        s => util::parse_ttl(s).map(|timeout| Some(timeout.unwrap_or(0))),
+    }
+}
+
+/// An empty port leaves it out of URLs.
+fn parse_port(s: &str) -> Result<Option<u16>, String> {
+    match s.trim() {
+        "" => Ok(None),
+        port => match port.parse::<u16>() {
+            Ok(port) if port > 0 => Ok(Some(port)),
+            _ => Err(format!("invalid port '{}', expected 1-65535", port)),
+        },
+    }
+}
+
+/// The socket path is only listened on by default or if given explicitly.
+fn parse_listen(
+    listen: Option<&str>,
+    explicit_socket_path: Option<&str>,
+    socket_path: &str,
+) -> Result<Vec<ListenAddr>, String> {
+    let mut addrs = listen.unwrap_or("").split(',')
+        .filter(|addr| !addr.trim().is_empty())
+        .map(|addr| ListenAddr::parse(addr.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if addrs.is_empty() || explicit_socket_path.is_some() {
+        addrs.insert(0, ListenAddr::Unix(socket_path.to_owned()));
+    }
+
+    Ok(addrs)
+}
+
+fn command_from_matches(matches: &clap::ArgMatches<'_>) -> Option<Command> {
+    // required arguments are enforced by clap
+    let arg = |m: &clap::ArgMatches<'_>, name| m.value_of(name).unwrap_or("").to_owned();
+
+    let command = match matches.subcommand() {
+        ("list", _) => Command::List,
+        ("show", Some(m)) => Command::Show(arg(m, "id")),
+        ("delete", Some(m)) => Command::Delete(arg(m, "id")),
+        ("rename", Some(m)) => Command::Rename(arg(m, "id"), arg(m, "name")),
+        ("gc", Some(m)) => Command::Gc {
+            report_only: m.is_present("report_only"),
+            rehash: m.is_present("rehash"),
+        },
+        ("stats", _) => Command::Stats,
+        ("verify", _) => Command::Verify,
+        ("key", Some(m)) => match m.subcommand() {
+            ("add", Some(m)) => Command::KeyAdd(
+                arg(m, "name"), Scope::parse_list(&arg(m, "scopes")).unwrap_or_default()),
+            ("revoke", Some(m)) => Command::KeyRevoke(arg(m, "name")),
+            ("list", _) => Command::KeyList,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(command)
+}
+
+fn parse_mime_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|mime| mime.trim().to_lowercase())
+        .filter(|mime| !mime.is_empty())
+        .collect()
+}
+
+fn trim_slashes(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Accepts domain names and IP addresses, IPv6 addresses in brackets.
+fn is_valid_hostname(hostname: &str) -> bool {
+    if let Some(ip) = hostname.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return ip.parse::<std::net::Ipv6Addr>().is_ok();
     }
+
+    !hostname.is_empty() && hostname.len() <= 253 && hostname.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty() && segment.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+}
+
+/// Two paths collide if one of them is a prefix of the other,
+/// compared segment by segment.
+fn paths_collide(a: &str, b: &str) -> bool {
+    a.split('/').zip(b.split('/')).all(|(x, y)| x == y)
 }
 
-fn config_to_struct(matches: clap::ArgMatches<'_>) -> Config {
-    Config {
-        socket_path:   Arc::from(matches.value_of("socket_path").unwrap_or("./urlnao.sock")),
-        db_path:       Arc::from(matches.value_of("db_path").unwrap_or("./db")),
-        hostname:      Arc::from(matches.value_of("hostname").unwrap_or("localhost")),
-        port:          Arc::from(matches.value_of("port").unwrap_or("23523")),
-        protocol:      Arc::from(matches.value_of("protocol").unwrap_or("http")),
-        shortid_path:  Arc::from(matches.value_of("shortid_path").unwrap_or("f")),
-        download_path: Arc::from(matches.value_of("download_path").unwrap_or("d")),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_colliding_paths() {
+        assert!(paths_collide("f", "f"));
+        assert!(paths_collide("files/s", "files"));
+        assert!(paths_collide("files", "files/s"));
+        assert!(paths_collide("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn allows_distinct_paths() {
+        assert!(!paths_collide("f", "d"));
+        assert!(!paths_collide("a/b", "a/c"));
+        assert!(!paths_collide("files", "files2"));
+        assert!(!paths_collide("fi", "files/s"));
     }
 }
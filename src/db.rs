@@ -1,178 +1,400 @@
+use crate::auth::ApiKey;
+use crate::error::Error;
+use crate::file::Storage;
+use crate::metrics;
+use crate::migrate;
+use crate::util;
+
 use rand::{
     prelude::*,
     distributions::Alphanumeric
 };
+use serde::{Deserialize, Serialize};
 use sled::{
     Transactional,
+    transaction::{ConflictableTransactionResult, TransactionalTree},
     transaction::ConflictableTransactionError::Abort
 };
+use tracing::{debug, info};
 
+use std::cell::Cell;
+use std::ops::Bound;
 use std::str::from_utf8;
 use std::sync::Arc;
 
 pub struct Upload {
-    pub id:        String,
-    pub checksum:  String,
-    pub orig_name: Option<String>,
+    pub id:       String,
+    pub checksum: String,
+    pub metadata: Metadata,
+}
+
+// bumped whenever the record changes in a way older versions cannot read
+pub const METADATA_VERSION: u32 = 1;
+
+/// Everything known about a single upload, stored as JSON in `id_to_meta`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub version:     u32,
+    /// unknown for uploads made before upload times were recorded
+    pub uploaded_at: Option<u64>,
+    pub size:        Option<u64>,
+    pub mime_type:   String,
+    /// unknown for uploads made before filenames were stored per upload
+    pub filename:    Option<String>,
+    pub field_name:  Option<String>,
+    pub uploader:    Uploader,
 }
 
-pub async fn open(db_path: Arc<str>) -> Result<sled::Db, String> {
-    let db = sled::open(db_path.to_string())
-        .map_err(|e| e.to_string())?;
+/// The identity of a client: the address the proxy added to `X-Forwarded-For`
+/// or the peer of a TCP connection, the user the proxy authenticated and the
+/// name of its API key if it used one.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Uploader {
+    pub forwarded_for: Option<String>,
+    pub remote_user:   Option<String>,
+    #[serde(default)]
+    pub api_key:       Option<String>,
+}
+
+impl Metadata {
+    pub fn new(
+        filename: &str,
+        field_name: &str,
+        size: u64,
+        mime_type: &str,
+        uploader: Uploader,
+    ) -> Metadata {
+        Metadata {
+            version: METADATA_VERSION,
+            uploaded_at: Some(util::unix_now()),
+            size: Some(size),
+            mime_type: mime_type.to_owned(),
+            filename: Some(filename.to_owned()),
+            field_name: Some(field_name.to_owned()),
+            uploader,
+        }
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(self)
+            .map_err(|e| Error::Corrupted(format!("failed to serialize metadata: {}", e)))
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Metadata, Error> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+
+        // check the version first, newer records may not parse at all
+        let versioned: Versioned = serde_json::from_slice(bytes)
+            .map_err(|e| Error::Corrupted(format!("invalid metadata: {}", e)))?;
+
+        if versioned.version > METADATA_VERSION {
+            return Err(Error::Corrupted(
+                format!("metadata version {} is not supported", versioned.version)));
+        }
+
+        serde_json::from_slice(bytes)
+            .map_err(|e| Error::Corrupted(format!("invalid metadata: {}", e)))
+    }
+}
+
+/// The orders in which uploads can be read straight from the database.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScanOrder {
+    Id,
+    Created,
+    Size,
+    Name,
+}
+
+pub async fn open(db_path: Arc<str>, storage: &Storage) -> Result<sled::Db, Error> {
+    let db = open_locked(&db_path)?;
+
+    migrate::run(&db, storage)?;
 
     Ok(db)
 }
 
-pub async fn get_all_ids_and_names(
-    db: sled::Db,
-) -> Result<Vec<Upload>, String> {
-    let mut entries = vec![];
-
-    let id_to_sha = db.open_tree(b"id_to_sha")
-        .map_err(|e| e.to_string())?;
-
-    let sha_to_orig = db.open_tree(b"sha_to_orig")
-        .map_err(|e| e.to_string())?;
-
-    for tuple in id_to_sha.iter() {
-        let (id_ivec, sha_ivec) = tuple
-            .map_err(|e| e.to_string())?;
-
-        let id = from_utf8(&id_ivec)
-            .map_err(|e| e.to_string())?;
-
-        let sha = from_utf8(&sha_ivec)
-            .map_err(|e| e.to_string())?;
-
-        let query_result = sha_to_orig.get(&sha_ivec)
-            .map_err(|e| e.to_string())?;
-
-        let orig_ivec = match query_result {
-           Some(ivec) => ivec,
-           None => {
-               entries.push(Upload {
-                   id: id.to_owned(),
-                   checksum: sha.to_owned(),
-                   orig_name: None,
-               });
-               continue
-           },
+/// Prints the changes of pending migrations, leaving the database untouched.
+pub async fn dry_run_migrations(db_path: Arc<str>, storage: &Storage) -> Result<(), Error> {
+    let db = open_locked(&db_path)?;
+
+    migrate::dry_run(&db, storage)
+}
+
+/// Opens the database, which sled locks for the lifetime of the process.
+fn open_locked(db_path: &str) -> Result<sled::Db, Error> {
+    match sled::open(db_path) {
+        // sled does not tell lock failures apart from other I/O errors
+        Err(sled::Error::Io(e)) if e.to_string().starts_with("could not acquire lock") => {
+            Err(Error::Locked)
+        },
+        result => Ok(result?),
+    }
+}
+
+/// Returns uploads in the given order, starting right after the entry
+/// with key `after`. Keys are short ids when ordering by id, otherwise
+/// [`created_key`]s, [`size_key`]s or [`name_key`]s.
+pub fn scan_uploads(
+    db: &sled::Db,
+    order: ScanOrder,
+    descending: bool,
+    after: Option<&[u8]>,
+) -> Result<impl Iterator<Item = Result<Upload, Error>>, Error> {
+    let index = match order {
+        ScanOrder::Id => db.open_tree(b"id_to_sha")?,
+        ScanOrder::Created => db.open_tree(b"created_to_id")?,
+        ScanOrder::Size => db.open_tree(b"size_to_id")?,
+        ScanOrder::Name => db.open_tree(b"name_to_id")?,
+    };
+
+    let range = match (after, descending) {
+        (None, _) => index.iter(),
+        (Some(key), false) => index.range::<&[u8], _>((Bound::Excluded(key), Bound::Unbounded)),
+        (Some(key), true) => index.range::<&[u8], _>(..key),
+    };
+
+    let keys: Box<dyn Iterator<Item = sled::Result<sled::IVec>>> = match descending {
+        true => Box::new(range.keys().rev()),
+        false => Box::new(range.keys()),
+    };
+
+    let trees = UploadTrees::open(db)?;
+
+    let uploads = keys.filter_map(move |key| {
+        let id = match (key, order) {
+            (Ok(key), ScanOrder::Id) => key,
+            (Ok(key), ScanOrder::Created | ScanOrder::Size) => key.subslice(8, key.len() - 8),
+            (Ok(key), ScanOrder::Name) => {
+                let start = key.iter().rposition(|b| *b == 0).map_or(0, |i| i + 1);
+                key.subslice(start, key.len() - start)
+            },
+            (Err(e), _) => return Some(Err(Error::from(e))),
         };
+        // the upload may have been removed after the key was read
+        trees.get(&id).transpose()
+    });
 
-        let orig = from_utf8(&orig_ivec)
-            .map_err(|e| e.to_string())?;
+    Ok(uploads)
+}
 
-        entries.push(Upload {
-            id: id.to_owned(),
-            checksum: sha.to_owned(),
-            orig_name: Some(orig.to_owned()),
-        });
+/// Returns the total number of uploads and the number of bytes they take up,
+/// counting only those made with the API key named `owner` if given.
+pub async fn get_totals(db: sled::Db, owner: Option<&str>) -> Result<(u64, u64), Error> {
+    let totals = db.open_tree(b"totals")?;
+
+    Ok(totals.get(totals_key(owner))?.map_or((0, 0), |ivec| totals_from_ivec(&ivec)))
+}
+
+/// The key of a short id in `created_to_id`, sorting by upload time first.
+pub fn created_key(created: u64, short_id: &[u8]) -> Vec<u8> {
+    let mut key = created.to_be_bytes().to_vec();
+    key.extend_from_slice(short_id);
+    key
+}
+
+/// The key of a short id in `expiry_to_id`, sorting by expiry time first.
+pub fn expiry_key(expiry: u64, short_id: &[u8]) -> Vec<u8> {
+    created_key(expiry, short_id)
+}
+
+/// The key of a short id in `size_to_id`, sorting by size first.
+pub fn size_key(size: u64, short_id: &[u8]) -> Vec<u8> {
+    created_key(size, short_id)
+}
+
+/// The key of a short id in `name_to_id`, sorting by filename first.
+/// Filenames cannot contain NUL bytes, which separate them from the id.
+pub fn name_key(filename: &str, short_id: &[u8]) -> Vec<u8> {
+    let mut key = filename.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(short_id);
+    key
+}
+
+/// The key of the running totals in `totals`, for all uploads or for those
+/// made with the API key named `owner`.
+pub fn totals_key(owner: Option<&str>) -> Vec<u8> {
+    match owner {
+        Some(name) => format!("key:{}", name).into_bytes(),
+        None => b"uploads".to_vec(),
     }
+}
 
-    Ok(entries)
+/// Totals are stored as the number of uploads followed by their bytes.
+pub fn totals_from_ivec(ivec: &sled::IVec) -> (u64, u64) {
+    (u64_from_ivec(ivec), u64_from_ivec(&ivec.subslice(8, 8)))
 }
 
-pub async fn try_get_sha_and_orig(
+pub fn totals_to_vec(uploads: u64, bytes: u64) -> Vec<u8> {
+    let mut value = uploads.to_be_bytes().to_vec();
+    value.extend_from_slice(&bytes.to_be_bytes());
+    value
+}
+
+/// Adds an upload to or removes it from the indexes it is sorted by and
+/// the totals it is counted in.
+fn update_indexes(
+    (tx_created_id, tx_size_id, tx_name_id, tx_totals): (&TransactionalTree, &TransactionalTree,
+        &TransactionalTree, &TransactionalTree),
+    short_id: &[u8],
+    metadata: &Metadata,
+    added: bool,
+) -> ConflictableTransactionResult<(), Error> {
+    // uploads made before upload times and sizes were recorded are indexed at 0
+    let size = metadata.size.unwrap_or(0);
+    let keys = [
+        (tx_created_id, created_key(metadata.uploaded_at.unwrap_or(0), short_id)),
+        (tx_size_id, size_key(size, short_id)),
+        (tx_name_id, name_key(metadata.filename.as_deref().unwrap_or(""), short_id)),
+    ];
+    for (tx_tree, key) in keys.iter() {
+        match added {
+            true => tx_tree.insert(key.as_slice(), &[])?,
+            false => tx_tree.remove(key.as_slice())?,
+        };
+    }
+
+    let mut owners = vec![None];
+    if let Some(name) = metadata.uploader.api_key.as_deref() {
+        owners.push(Some(name));
+    }
+
+    for owner in owners {
+        let key = totals_key(owner);
+        let (uploads, bytes) = tx_totals.get(&key)?.map_or((0, 0), |ivec| totals_from_ivec(&ivec));
+        let (uploads, bytes) = match added {
+            true => (uploads + 1, bytes + size),
+            false => (uploads.saturating_sub(1), bytes.saturating_sub(size)),
+        };
+        tx_totals.insert(key, totals_to_vec(uploads, bytes))?;
+    }
+
+    Ok(())
+}
+
+struct UploadTrees {
+    id_to_sha:  sled::Tree,
+    id_to_meta: sled::Tree,
+}
+
+impl UploadTrees {
+    fn open(db: &sled::Db) -> Result<Self, Error> {
+        Ok(UploadTrees {
+            id_to_sha:  db.open_tree(b"id_to_sha")?,
+            id_to_meta: db.open_tree(b"id_to_meta")?,
+        })
+    }
+
+    fn get(&self, short_id: &[u8]) -> Result<Option<Upload>, Error> {
+        let sha_ivec = match self.id_to_sha.get(short_id)? {
+            Some(ivec) => ivec,
+            None => return Ok(None),
+        };
+
+        let meta_ivec = match self.id_to_meta.get(short_id)? {
+            Some(ivec) => ivec,
+            None => return Err(Error::Corrupted("short id without metadata".to_string())),
+        };
+
+        Ok(Some(Upload {
+            id: from_utf8(short_id)?.to_owned(),
+            checksum: from_utf8(&sha_ivec)?.to_owned(),
+            metadata: Metadata::from_slice(&meta_ivec)?,
+        }))
+    }
+}
+
+pub async fn try_get_sha_and_metadata(
     db: sled::Db,
     short_id: &[u8]
-) -> Result<(String, String), String> {
-    let id_to_sha = db.open_tree(b"id_to_sha")
-        .map_err(|e| e.to_string())?;
+) -> Result<(String, Metadata), Error> {
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
 
-    let sha_to_orig = db.open_tree(b"sha_to_orig")
-        .map_err(|e| e.to_string())?;
+    let id_to_meta = db.open_tree(b"id_to_meta")?;
 
-    let query_result = id_to_sha.get(short_id)
-        .map_err(|e| e.to_string())?;
+    let query_result = id_to_sha.get(short_id)?;
 
     let sha256_ivec = match query_result {
         Some(ivec) => ivec,
-        None => return Err("failed to find checksum in db tree".to_string()),
+        None => return Err(Error::NotFound),
     };
 
-    let sha256 = from_utf8(&sha256_ivec)
-        .map_err(|e| e.to_string())?;
+    let sha256 = from_utf8(&sha256_ivec)?;
 
-    let query_result = sha_to_orig.get(&sha256_ivec)
-        .map_err(|e| e.to_string())?;
+    let query_result = id_to_meta.get(short_id)?;
 
-    let orig_ivec = match query_result {
-        Some(ivec) => ivec,
-        None => return Err("failed to find original filename in db tree".to_string()),
-    };
-
-    let orig = from_utf8(&orig_ivec)
-        .map_err(|e| e.to_string())?;
-
-    Ok((sha256.to_owned(), orig.to_owned()))
+    match query_result {
+        Some(ivec) => Ok((sha256.to_owned(), Metadata::from_slice(&ivec)?)),
+        None => Err(Error::Corrupted("short id without metadata".to_string())),
+    }
 }
 
-pub async fn try_get_sha_for_orig(
+/// Resolves the legacy `/d/<name>` links created before names were stored
+/// per short id to the short id of the last upload with that name.
+pub async fn try_get_id_for_orig(
     db: sled::Db,
     filename: &[u8]
-) -> Result<String, String> {
-    let orig_to_sha = db.open_tree(b"orig_to_sha")
-        .map_err(|e| e.to_string())?;
+) -> Result<String, Error> {
+    let orig_to_id = db.open_tree(b"orig_to_id")?;
 
-    let query_result = orig_to_sha.get(filename)
-        .map_err(|e| e.to_string())?;
+    let query_result = orig_to_id.get(filename)?;
 
-    let sha256_ivec = match query_result {
+    let id_ivec = match query_result {
         Some(ivec) => ivec,
-        None => return Err("unknown filename".to_string()),
+        None => return Err(Error::NotFound),
     };
 
-    let sha256 = from_utf8(&sha256_ivec)
-        .map_err(|e| e.to_string())?;
-
-    Ok(sha256.to_owned())
+    Ok(from_utf8(&id_ivec)?.to_owned())
 }
 
-pub async fn try_add_sha_orig(db: sled::Db, sha256: &str, orig: &str) -> Result<(), String> {
-    let sha_to_orig = db.open_tree(b"sha_to_orig")
-        .map_err(|e| e.to_string())?;
+/// Records a new upload under a new short id. Uploads of content that is
+/// already stored get a record of their own and share the blob, which is
+/// referenced in `sha_refs` before it is moved into place.
+pub async fn try_get_new_shortid(
+    db: sled::Db,
+    sha256: &str,
+    metadata: &Metadata,
+    expiry: Option<u64>,
+    token_hash: &[u8],
+) -> Result<String, Error> {
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
 
-    let orig_to_sha = db.open_tree(b"orig_to_sha")
-        .map_err(|e| e.to_string())?;
+    let id_to_expiry = db.open_tree(b"id_to_expiry")?;
 
-    println!("adding {} with orig name {}", sha256, orig);
+    let id_to_tokens = db.open_tree(b"id_to_tokens")?;
 
-    (&sha_to_orig, &orig_to_sha)
-        .transaction(|(tx_sha_orig, tx_orig_sha)| {
-            tx_sha_orig.insert(sha256.as_bytes(), orig.as_bytes())?;
+    let id_to_meta = db.open_tree(b"id_to_meta")?;
 
-            tx_orig_sha.insert(orig.as_bytes(), sha256.as_bytes())?;
+    let expiry_to_id = db.open_tree(b"expiry_to_id")?;
 
-            Ok(())
+    let gone = db.open_tree(b"gone")?;
 
-        })
-    .map_err(|e: sled::transaction::TransactionError<&str>| {
-        e.to_string()
-    })?;
+    let created_to_id = db.open_tree(b"created_to_id")?;
 
-    Ok(())
-}
+    let size_to_id = db.open_tree(b"size_to_id")?;
 
-pub async fn try_get_new_shortid(db: sled::Db, sha256: &str) -> Result<String, String> {
-    let sha_to_id = db.open_tree(b"sha_to_id")
-        .map_err(|e| e.to_string())?;
+    let name_to_id = db.open_tree(b"name_to_id")?;
 
-    let id_to_sha = db.open_tree(b"id_to_sha")
-        .map_err(|e| e.to_string())?;
+    let totals = db.open_tree(b"totals")?;
 
-    let new_id = (&sha_to_id, &id_to_sha)
-        .transaction(|(tx_sha_id, tx_id_sha)| {
-            // check if file with same hash is already in db
-            let query_result = tx_sha_id.get(sha256.as_bytes())?;
+    let sha_refs = db.open_tree(b"sha_refs")?;
 
-            if let Some(x) = query_result {
-                let id = from_utf8(&x).map_err(|_| {
-                    Abort("failed to convert query result to string")
-                })?;
+    debug!("adding {} with orig name {}", sha256, metadata.filename.as_deref().unwrap_or(""));
 
-                println!("Info: reusing existing ID for duplicate upload: {}", id);
-                return Ok(id.to_string());
-            }
+    // a conflicting transaction runs again, only its last run is counted
+    let reused = Cell::new(false);
+    let retries = Cell::new(0);
+
+    let new_id = (&id_to_sha, &id_to_expiry, &expiry_to_id, &id_to_tokens, &id_to_meta, &gone,
+                  &created_to_id, &size_to_id, &name_to_id, &totals, &sha_refs)
+        .transaction(|(tx_id_sha, tx_id_expiry, tx_expiry_id, tx_id_tokens, tx_id_meta, tx_gone,
+                       tx_created_id, tx_size_id, tx_name_id, tx_totals, tx_sha_refs)| {
+            reused.set(false);
+            retries.set(0);
 
             // try five times to find an unused short id
             for _ in 0..5 {
@@ -184,18 +406,382 @@ pub async fn try_get_new_shortid(db: sled::Db, sha256: &str) -> Result<String, S
                     .collect();
                 // check if short id is already in use
                 if let Ok(Some(_)) = tx_id_sha.get(new_id.as_bytes()) {
+                    retries.set(retries.get() + 1);
+                    continue
+                }
+                // removed short ids are not reused, they keep answering 410
+                if let Ok(Some(_)) = tx_gone.get(new_id.as_bytes()) {
+                    retries.set(retries.get() + 1);
                     continue
                 }
-                // try to insert mapping sha256 -> new_id
                 tx_id_sha.insert(new_id.as_bytes(), sha256.as_bytes())?;
-                tx_sha_id.insert(sha256.as_bytes(), new_id.as_bytes())?;
+                tx_id_tokens.insert(new_id.as_bytes(), token_hash)?;
+                tx_id_meta.insert(new_id.as_bytes(), metadata.to_vec().map_err(Abort)?)?;
+                update_indexes((tx_created_id, tx_size_id, tx_name_id, tx_totals),
+                    new_id.as_bytes(), metadata, true)?;
+
+                if let Some(expiry) = expiry {
+                    tx_id_expiry.insert(new_id.as_bytes(), &expiry.to_be_bytes())?;
+                    tx_expiry_id.insert(expiry_key(expiry, new_id.as_bytes()), &[])?;
+                }
+
+                let refs = tx_sha_refs.get(sha256.as_bytes())?
+                    .map_or(0, |ivec| u64_from_ivec(&ivec));
+                tx_sha_refs.insert(sha256.as_bytes(), &(refs + 1).to_be_bytes())?;
+                reused.set(refs > 0);
 
                 return Ok(new_id);
             };
-            return Err(Abort("failed to find a free short id"));
-        }).map_err(|e: sled::transaction::TransactionError<&str>| {
-          e.to_string()
-      })?;
+            Err(Abort(Error::NoFreeShortId))
+        });
+
+    metrics::SHORT_ID_RETRIES.add(retries.get());
+    if reused.get() && new_id.is_ok() {
+        info!("reusing existing blob for duplicate upload: {}", sha256);
+        metrics::DEDUP_HITS.inc();
+    }
+
+    Ok(new_id?)
+}
+
+pub async fn try_get_expiry(db: sled::Db, short_id: &[u8]) -> Result<Option<u64>, Error> {
+    let id_to_expiry = db.open_tree(b"id_to_expiry")?;
+
+    Ok(id_to_expiry.get(short_id)?.map(|ivec| u64_from_ivec(&ivec)))
+}
+
+/// Changes the filename of an upload, returning `false` if there is no such upload.
+pub async fn try_rename(db: sled::Db, short_id: &[u8], filename: &str) -> Result<bool, Error> {
+    let id_to_meta = db.open_tree(b"id_to_meta")?;
+
+    let name_to_id = db.open_tree(b"name_to_id")?;
+
+    (&id_to_meta, &name_to_id)
+        .transaction(|(tx_id_meta, tx_name_id)| {
+            let mut metadata = match tx_id_meta.get(short_id)? {
+                Some(ivec) => Metadata::from_slice(&ivec).map_err(Abort)?,
+                None => return Ok(false),
+            };
+
+            tx_name_id.remove(name_key(metadata.filename.as_deref().unwrap_or(""), short_id))?;
+            tx_name_id.insert(name_key(filename, short_id), &[])?;
+
+            metadata.filename = Some(filename.to_owned());
+            tx_id_meta.insert(short_id, metadata.to_vec().map_err(Abort)?)?;
+
+            Ok(true)
+        })
+        .map_err(Error::from)
+}
+
+/// Returns whether a short id has expired or was deleted, regardless of
+/// whether the reaper has already removed it.
+pub async fn is_gone(db: sled::Db, short_id: &[u8]) -> Result<bool, Error> {
+    let gone = db.open_tree(b"gone")?;
+
+    let id_to_expiry = db.open_tree(b"id_to_expiry")?;
+
+    if gone.contains_key(short_id)? {
+        return Ok(true);
+    }
+
+    let query_result = id_to_expiry.get(short_id)?;
+
+    match query_result {
+        Some(ivec) => Ok(u64_from_ivec(&ivec) <= util::unix_now()),
+        None => Ok(false),
+    }
+}
+
+/// Removes all short ids that expired before `now` and returns the checksums
+/// of all blobs that are no longer referenced by any short id.
+pub async fn remove_expired(db: sled::Db, now: u64) -> Result<Vec<String>, Error> {
+    let expiry_to_id = db.open_tree(b"expiry_to_id")?;
+
+    // only the keys of uploads that expired are read
+    let mut expired_ids = vec![];
+
+    for key in expiry_to_id.range(..expiry_key(now.saturating_add(1), b"")).keys() {
+        let key = key?;
+        expired_ids.push(key.subslice(8, key.len() - 8));
+    }
+
+    let mut unreferenced = vec![];
+
+    for id in expired_ids {
+        let removed = match try_remove_id(db.clone(), &id, now).await? {
+            Some(removed) => removed,
+            None => continue,
+        };
+
+        info!("short id {} expired", String::from_utf8_lossy(&id));
+
+        if let Some(sha) = unreferenced_sha(removed)? {
+            unreferenced.push(sha);
+        }
+    }
+
+    Ok(unreferenced)
+}
+
+pub enum Deletion {
+    UnknownId,
+    Denied,
+    /// holds the checksum of the blob if it is no longer referenced
+    Deleted(Option<String>),
+}
+
+/// What entitles a client to delete an upload.
+pub enum Proof<'a> {
+    /// the hash of one of the upload's deletion tokens
+    Token(&'a [u8]),
+    /// the name of the API key the upload was made with
+    Owner(&'a str),
+    Admin,
+}
+
+pub async fn try_delete(
+    db: sled::Db,
+    short_id: &[u8],
+    proof: Proof<'_>,
+) -> Result<Deletion, Error> {
+    let id_to_tokens = db.open_tree(b"id_to_tokens")?;
+
+    let id_to_meta = db.open_tree(b"id_to_meta")?;
+
+    let query_result = id_to_tokens.get(short_id)?;
+
+    let tokens = match query_result {
+        Some(ivec) => ivec,
+        None => return Ok(Deletion::UnknownId),
+    };
+
+    let permitted = match proof {
+        Proof::Token(token_hash) => tokens.chunks(token_hash.len()).any(|t| t == token_hash),
+        Proof::Owner(name) => match id_to_meta.get(short_id)? {
+            Some(ivec) => Metadata::from_slice(&ivec)?.uploader.api_key.as_deref() == Some(name),
+            None => false,
+        },
+        Proof::Admin => true,
+    };
+
+    if !permitted {
+        return Ok(Deletion::Denied);
+    }
+
+    let removed = match try_remove_id(db.clone(), short_id, util::unix_now()).await? {
+        Some(removed) => removed,
+        None => return Ok(Deletion::UnknownId),
+    };
+
+    info!("short id {} deleted", String::from_utf8_lossy(short_id));
+
+    Ok(Deletion::Deleted(unreferenced_sha(removed)?))
+}
+
+/// Removes all mappings of a short id and marks it as gone, returning the
+/// checksum it referred to and whether that was the last reference.
+async fn try_remove_id(
+    db: sled::Db,
+    short_id: &[u8],
+    now: u64,
+) -> Result<Option<(sled::IVec, bool)>, Error> {
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
+
+    let id_to_expiry = db.open_tree(b"id_to_expiry")?;
+
+    let expiry_to_id = db.open_tree(b"expiry_to_id")?;
+
+    let id_to_tokens = db.open_tree(b"id_to_tokens")?;
+
+    let id_to_meta = db.open_tree(b"id_to_meta")?;
+
+    let gone = db.open_tree(b"gone")?;
+
+    let created_to_id = db.open_tree(b"created_to_id")?;
+
+    let size_to_id = db.open_tree(b"size_to_id")?;
+
+    let name_to_id = db.open_tree(b"name_to_id")?;
+
+    let totals = db.open_tree(b"totals")?;
+
+    let sha_refs = db.open_tree(b"sha_refs")?;
+
+    (&id_to_sha, &id_to_expiry, &expiry_to_id, &id_to_tokens, &id_to_meta, &gone,
+     &created_to_id, &size_to_id, &name_to_id, &totals, &sha_refs)
+        .transaction(|(tx_id_sha, tx_id_expiry, tx_expiry_id, tx_id_tokens, tx_id_meta, tx_gone,
+                       tx_created_id, tx_size_id, tx_name_id, tx_totals, tx_sha_refs)| {
+            let sha_ivec = match tx_id_sha.remove(short_id)? {
+                Some(ivec) => ivec,
+                // already removed concurrently
+                None => return Ok(None),
+            };
+
+            if let Some(ivec) = tx_id_expiry.remove(short_id)? {
+                tx_expiry_id.remove(expiry_key(u64_from_ivec(&ivec), short_id))?;
+            }
+            tx_id_tokens.remove(short_id)?;
+            if let Some(ivec) = tx_id_meta.remove(short_id)? {
+                let metadata = Metadata::from_slice(&ivec).map_err(Abort)?;
+                update_indexes((tx_created_id, tx_size_id, tx_name_id, tx_totals),
+                    short_id, &metadata, false)?;
+            }
+            tx_gone.insert(short_id, &now.to_be_bytes())?;
+
+            let refs = tx_sha_refs.get(&sha_ivec)?.map_or(0, |ivec| u64_from_ivec(&ivec));
+            let unreferenced = refs <= 1;
+            match unreferenced {
+                true => tx_sha_refs.remove(&sha_ivec)?,
+                false => tx_sha_refs.insert(&sha_ivec, &(refs - 1).to_be_bytes())?,
+            };
+
+            Ok(Some((sha_ivec, unreferenced)))
+        })
+        .map_err(Error::from)
+}
+
+/// Returns the checksum a removed short id referred to if its blob can be removed.
+fn unreferenced_sha((sha256, unreferenced): (sled::IVec, bool)) -> Result<Option<String>, Error> {
+    match unreferenced {
+        true => Ok(Some(from_utf8(&sha256)?.to_owned())),
+        false => Ok(None),
+    }
+}
+
+/// Removes the marks of short ids that were removed before `before`, which
+/// answer 404 instead of 410 from then on and may be issued again.
+/// Returns their number.
+pub async fn remove_tombstones(db: sled::Db, before: u64, report_only: bool) -> Result<usize, Error> {
+    let gone = db.open_tree(b"gone")?;
+
+    let mut removed = 0;
+
+    for tuple in gone.iter() {
+        let (id_ivec, removed_ivec) = tuple?;
+
+        if u64_from_ivec(&removed_ivec) >= before {
+            continue
+        }
+
+        removed += 1;
+        if !report_only {
+            gone.remove(&id_ivec)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Returns whether any short id refers to a checksum.
+pub fn is_sha_referenced(db: &sled::Db, sha256: &str) -> Result<bool, Error> {
+    let sha_refs = db.open_tree(b"sha_refs")?;
+
+    Ok(sha_refs.contains_key(sha256.as_bytes())?)
+}
+
+/// Returns all short ids together with the checksum they refer to.
+pub async fn get_id_shas(db: sled::Db) -> Result<Vec<(String, String)>, Error> {
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
+
+    id_to_sha.iter()
+        .map(|tuple| {
+            let (id_ivec, sha_ivec) = tuple?;
+            Ok((from_utf8(&id_ivec)?.to_owned(), from_utf8(&sha_ivec)?.to_owned()))
+        })
+        .collect()
+}
+
+/// Removes entries of the legacy `orig_to_id` tree whose short id is neither
+/// in use nor marked as gone, returning their number. Names of removed
+/// uploads are kept while their short id answers 410.
+pub async fn remove_dangling_names(db: sled::Db, report_only: bool) -> Result<usize, Error> {
+    let orig_to_id = db.open_tree(b"orig_to_id")?;
+
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
+
+    let gone = db.open_tree(b"gone")?;
+
+    let mut dangling = 0;
+
+    for tuple in orig_to_id.iter() {
+        let (orig_ivec, id_ivec) = tuple?;
+
+        if id_to_sha.contains_key(&id_ivec)? || gone.contains_key(&id_ivec)? {
+            continue
+        }
+
+        dangling += 1;
+        if !report_only {
+            orig_to_id.remove(&orig_ivec)?;
+        }
+    }
+
+    Ok(dangling)
+}
+
+pub fn u64_from_ivec(ivec: &sled::IVec) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&ivec[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+pub async fn get_api_key(db: sled::Db, key_hash: &[u8]) -> Result<Option<ApiKey>, Error> {
+    let api_keys = db.open_tree(b"api_keys")?;
+
+    match api_keys.get(key_hash)? {
+        Some(ivec) => Ok(Some(api_key_from_slice(&ivec)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_api_keys(db: sled::Db) -> Result<Vec<ApiKey>, Error> {
+    let api_keys = db.open_tree(b"api_keys")?;
+
+    api_keys.iter().values()
+        .map(|value| api_key_from_slice(&value?))
+        .collect()
+}
+
+/// Stores a new API key, returning `false` if its name is already taken.
+/// Keys are only managed while the server is stopped, so this does not
+/// race with other changes.
+pub async fn try_add_api_key(db: sled::Db, key_hash: &[u8], key: &ApiKey) -> Result<bool, Error> {
+    let api_keys = db.open_tree(b"api_keys")?;
+
+    // keys are few, names are checked by looking at all of them
+    for value in api_keys.iter().values() {
+        if api_key_from_slice(&value?)?.name == key.name {
+            return Ok(false);
+        }
+    }
+
+    let value = serde_json::to_vec(key)
+        .map_err(|e| Error::Corrupted(format!("failed to serialize API key: {}", e)))?;
+
+    api_keys.insert(key_hash, value)?;
+    api_keys.flush_async().await?;
+
+    Ok(true)
+}
+
+/// Removes the API key with the given name, returning `false` if there is none.
+pub async fn try_revoke_api_key(db: sled::Db, name: &str) -> Result<bool, Error> {
+    let api_keys = db.open_tree(b"api_keys")?;
+
+    for tuple in api_keys.iter() {
+        let (key_hash, value) = tuple?;
+        if api_key_from_slice(&value)?.name == name {
+            api_keys.remove(key_hash)?;
+            api_keys.flush_async().await?;
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
 
-    Ok(new_id)
+fn api_key_from_slice(bytes: &[u8]) -> Result<ApiKey, Error> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| Error::Corrupted(format!("invalid API key: {}", e)))
 }
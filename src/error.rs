@@ -0,0 +1,185 @@
+use crate::auth::Scope;
+
+use warp::http::StatusCode;
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the request body is not valid multipart/form-data
+    MalformedMultipart(String),
+    MissingBoundary,
+    UnsupportedMediaType,
+    NoFiles,
+    InvalidTtl(String),
+    InvalidQuery(String),
+    MissingDeletionToken,
+    /// the request lacks a valid API key
+    Unauthorized,
+    /// the API key lacks the scope
+    Forbidden(Scope),
+    PayloadTooLarge(Limit),
+    EmptyFile,
+    NotFound,
+    NoFreeShortId,
+    /// the server no longer accepts uploads
+    ShuttingDown,
+    Db(sled::Error),
+    /// another process, usually the server, holds the database
+    Locked,
+    Io(std::io::Error),
+    Http(warp::http::Error),
+    /// the database holds data that does not have the expected shape
+    Corrupted(String),
+    /// the database was written by a newer version
+    UnsupportedSchema(u64),
+}
+
+/// The limit that was exceeded by an upload.
+#[derive(Debug)]
+pub enum Limit {
+    UploadSize(u64),
+    FileSize(u64),
+    Files(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::UploadSize(n) => write!(f, "uploads are limited to {} bytes", n),
+            Limit::FileSize(n)   => write!(f, "files are limited to {} bytes", n),
+            Limit::Files(n)      => write!(f, "uploads are limited to {} file(s)", n),
+        }
+    }
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::MalformedMultipart(_) |
+            Error::MissingBoundary |
+            Error::NoFiles |
+            Error::InvalidTtl(_) |
+            Error::InvalidQuery(_) |
+            Error::MissingDeletionToken  => StatusCode::BAD_REQUEST,
+            Error::Unauthorized          => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_)          => StatusCode::FORBIDDEN,
+            Error::UnsupportedMediaType  => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::PayloadTooLarge(_)    => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::EmptyFile             => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::NotFound              => StatusCode::NOT_FOUND,
+            Error::ShuttingDown          => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Io(e) if is_storage_full(e) => StatusCode::INSUFFICIENT_STORAGE,
+            Error::NoFreeShortId |
+            Error::Db(_) |
+            Error::Locked |
+            Error::Io(_) |
+            Error::Http(_) |
+            Error::Corrupted(_) |
+            Error::UnsupportedSchema(_)  => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A short name for the kind of error, e.g. for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::MalformedMultipart(_) => "malformed_multipart",
+            Error::MissingBoundary       => "missing_boundary",
+            Error::UnsupportedMediaType  => "unsupported_media_type",
+            Error::NoFiles               => "no_files",
+            Error::InvalidTtl(_)         => "invalid_ttl",
+            Error::InvalidQuery(_)       => "invalid_query",
+            Error::MissingDeletionToken  => "missing_deletion_token",
+            Error::Unauthorized          => "unauthorized",
+            Error::Forbidden(_)          => "forbidden",
+            Error::PayloadTooLarge(_)    => "payload_too_large",
+            Error::EmptyFile             => "empty_file",
+            Error::NotFound              => "not_found",
+            Error::NoFreeShortId         => "no_free_short_id",
+            Error::ShuttingDown          => "shutting_down",
+            Error::Db(_)                 => "db",
+            Error::Locked                => "locked",
+            Error::Io(e) if is_storage_full(e) => "storage_full",
+            Error::Io(_)                 => "io",
+            Error::Http(_)               => "http",
+            Error::Corrupted(_)          => "corrupted",
+            Error::UnsupportedSchema(_)  => "unsupported_schema",
+        }
+    }
+
+    /// A description that is safe to show to clients.
+    pub fn reason(&self) -> String {
+        match self.status() {
+            StatusCode::INTERNAL_SERVER_ERROR => "internal server error".to_string(),
+            StatusCode::INSUFFICIENT_STORAGE => "insufficient storage".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+fn is_storage_full(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MalformedMultipart(e) => write!(f, "malformed multipart body: {}", e),
+            Error::MissingBoundary       => write!(f, "multipart boundary is missing"),
+            Error::UnsupportedMediaType  => write!(f, "expected multipart/form-data"),
+            Error::NoFiles               => write!(f, "request contains no files"),
+            Error::InvalidTtl(e)         => write!(f, "{}", e),
+            Error::InvalidQuery(e)       => write!(f, "invalid query: {}", e),
+            Error::MissingDeletionToken  => write!(f, "deletion token is missing"),
+            Error::Unauthorized          => write!(f, "missing or invalid API key"),
+            Error::Forbidden(s)          => write!(f, "API key lacks the {} scope", s),
+            Error::PayloadTooLarge(l)    => write!(f, "payload too large: {}", l),
+            Error::EmptyFile             => write!(f, "file is empty"),
+            Error::NotFound              => write!(f, "not found"),
+            Error::NoFreeShortId         => write!(f, "failed to find a free short id"),
+            Error::ShuttingDown          => write!(f, "server is shutting down, try again later"),
+            Error::Db(e)                 => write!(f, "database error: {}", e),
+            Error::Locked                => write!(f, "database is in use by another process, \
+                stop the server first"),
+            Error::Io(e)                 => write!(f, "I/O error: {}", e),
+            Error::Http(e)               => write!(f, "failed to build response: {}", e),
+            Error::Corrupted(e)          => write!(f, "corrupted database entry: {}", e),
+            Error::UnsupportedSchema(v)  => write!(f, "database schema version {} is newer \
+                than the supported version {}, refusing to downgrade",
+                v, crate::migrate::SCHEMA_VERSION),
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Db(e)
+    }
+}
+
+impl From<sled::transaction::TransactionError<Error>> for Error {
+    fn from(e: sled::transaction::TransactionError<Error>) -> Self {
+        match e {
+            sled::transaction::TransactionError::Abort(e) => e,
+            sled::transaction::TransactionError::Storage(e) => Error::Db(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::Corrupted(e.to_string())
+    }
+}
+
+impl From<warp::http::Error> for Error {
+    fn from(e: warp::http::Error) -> Self {
+        Error::Http(e)
+    }
+}
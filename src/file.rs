@@ -1,62 +1,402 @@
+use crate::db;
+use crate::error::{Error, Limit};
+use crate::metrics;
+use crate::sniff;
 use crate::util;
 
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_util::TryStreamExt;
+use hyper::body::{Body, Sender};
 use sha2::Digest;
-use tokio::net::UnixListener;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{error, info, warn, Instrument};
 
-use std::fs::{
-    File,
-    Permissions,
-};
+use std::io::SeekFrom;
+use std::path::Path;
 use std::sync::Arc;
-use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::os::unix::fs::MetadataExt;
+use std::time::SystemTime;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
 
 pub struct FileInfo {
+    pub field_name:        Arc<str>,
     pub original_filename: Arc<str>,
-    pub uuid:              Arc<str>,
+    pub tmp_file:          TmpFile,
+    pub sha256:            Arc<str>,
+    pub size:              u64,
+    pub mime_type:         Arc<str>,
+}
+
+/// Where blobs and temporary files are kept.
+#[derive(Clone)]
+pub struct Storage {
+    uploads_dir: Arc<str>,
+    tmp_dir:     Arc<str>,
+}
+
+impl Storage {
+    /// Blobs are kept in `uploads/` below `data_dir`, temporary files
+    /// in `tmp/` below it unless another `tmp_dir` is given.
+    pub fn new(data_dir: &str, tmp_dir: Option<&str>) -> Self {
+        let data_dir = Path::new(data_dir);
+        let tmp_dir = match tmp_dir {
+            Some(tmp_dir) => tmp_dir.into(),
+            None => data_dir.join("tmp"),
+        };
+        Storage {
+            uploads_dir: Arc::from(data_dir.join("uploads").to_string_lossy()),
+            tmp_dir:     Arc::from(tmp_dir.to_string_lossy()),
+        }
+    }
+
+    pub fn uploads_dir(&self) -> &str {
+        &self.uploads_dir
+    }
+
+    pub fn tmp_dir(&self) -> &str {
+        &self.tmp_dir
+    }
+
+    /// Blobs are sharded by the first two bytes of their checksum,
+    /// e.g. `uploads/ab/cd/abcd…`, to keep directories small.
+    pub fn blob_path(&self, sha256: &str) -> String {
+        match (sha256.get(..2), sha256.get(2..4)) {
+            (Some(a), Some(b)) => format!("{}/{}/{}/{}", self.uploads_dir, a, b, sha256),
+            _ => format!("{}/{}", self.uploads_dir, sha256),
+        }
+    }
+
+    /// Where blobs were kept before they were sharded, see [`blob_path`](Self::blob_path).
+    pub fn flat_blob_path(&self, sha256: &str) -> String {
+        format!("{}/{}", self.uploads_dir, sha256)
+    }
+
+    pub fn tmp_path(&self, name: &str) -> String {
+        format!("{}/{}", self.tmp_dir, name)
+    }
+
+    /// Creates the directories and warns if they are on different filesystems.
+    pub fn prepare(&self) -> Result<(), Error> {
+        std::fs::create_dir_all(&*self.uploads_dir)?;
+
+        std::fs::create_dir_all(&*self.tmp_dir)?;
+
+        let uploads_dev = std::fs::metadata(&*self.uploads_dir)?.dev();
+        if std::fs::metadata(&*self.tmp_dir)?.dev() != uploads_dev {
+            warn!("{} and {} are on different filesystems, uploads are copied \
+                instead of moved", self.tmp_dir, self.uploads_dir);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn is_checksum(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
 }
 
-pub async fn setup_dirs_get_listener(socket_path: &Arc<str>) -> Result<UnixListener, String> {
-    let listener = UnixListener::bind(socket_path.to_string())
-        .map_err(|e| e.to_string())?;
+/// The number of [`TmpFile`]s that exist, i.e. files being uploaded.
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn tmp_files_in_flight() -> usize {
+    TMP_FILES.load(Ordering::Relaxed)
+}
+
+/// A uniquely named file in `tmp/` that is removed once dropped,
+/// unless it was moved to `uploads/` before.
+pub struct TmpFile {
+    path: String,
+}
+
+impl TmpFile {
+    pub fn new_random(storage: &Storage) -> Self {
+        TMP_FILES.fetch_add(1, Ordering::Relaxed);
+        TmpFile {
+            path: storage.tmp_path(&util::new_random_uuid()),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        TMP_FILES.fetch_sub(1, Ordering::Relaxed);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove temporary file {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// A part of a file to be sent, preceded by `prefix`.
+pub struct Segment {
+    pub prefix: Bytes,
+    pub start:  u64,
+    pub len:    u64,
+}
+
+/// Streams the given segments of a file in chunks, followed by `suffix`.
+pub fn stream_segments(
+    file: tokio::fs::File,
+    segments: Vec<Segment>,
+    suffix: Bytes,
+) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    // errors are logged with the ID of the request
+    tokio::spawn(async move {
+        if let Err(e) = send_segments(&mut sender, file, segments, suffix).await {
+            error!("failed to stream file: {}", e);
+            sender.abort();
+        }
+    }.instrument(tracing::Span::current()));
+
+    body
+}
+
+async fn send_segments(
+    sender: &mut Sender,
+    mut file: tokio::fs::File,
+    segments: Vec<Segment>,
+    suffix: Bytes,
+) -> Result<(), Error> {
+    for segment in segments {
+        // the client going away is not an error worth reporting
+        if !segment.prefix.is_empty() && sender.send_data(segment.prefix).await.is_err() {
+            return Ok(());
+        }
+
+        file.seek(SeekFrom::Start(segment.start)).await?;
+
+        let mut remaining = segment.len;
+        let mut buf = vec![0u8; CHUNK_SIZE as usize];
+
+        while remaining > 0 {
+            let n = remaining.min(CHUNK_SIZE) as usize;
+            let read = file.read(&mut buf[..n]).await?;
 
-    let socket_permissions = Permissions::from_mode(0o660);
+            if read == 0 {
+                return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
 
-    std::fs::set_permissions(socket_path.to_string(), socket_permissions)
-        .map_err(|e| e.to_string())?;
+            if sender.send_data(Bytes::copy_from_slice(&buf[..read])).await.is_err() {
+                return Ok(());
+            }
 
-    std::fs::create_dir_all("uploads")
-        .map_err(|e| e.to_string())?;
+            remaining -= read as u64;
+        }
+    }
 
-    std::fs::create_dir_all("tmp")
-        .map_err(|e| e.to_string())?;
+    if !suffix.is_empty() {
+        let _ = sender.send_data(suffix).await;
+    }
 
-    Ok(listener)
+    Ok(())
 }
 
-pub async fn get_sha256_of_file<'a>(path: &str) -> Result<String, String> {
-    let mut file = File::open(path).map_err(|e| e.to_string())?;
+/// Writes a stream of chunks to a temporary file, hashing them along the way.
+/// Returns the hex encoded SHA-256 checksum, the number of bytes written and
+/// the leading bytes for MIME type detection, failing as soon as more than
+/// `max_size` bytes were received.
+pub async fn write_and_hash<S, E>(
+    tmp_file: &TmpFile,
+    mut chunks: S,
+    max_size: u64,
+) -> Result<(String, u64, Vec<u8>), Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut file = tokio::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(tmp_file.path())
+        .await?;
 
     let mut sha256 = sha2::Sha256::new();
+    let mut size = 0;
+    let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
 
-    let n = std::io::copy(&mut file, &mut sha256).map_err(|e| e.to_string())?;
+    while let Some(bytes) = chunks.try_next().await
+        .map_err(|e| Error::MalformedMultipart(e.to_string()))? {
+        size += bytes.len() as u64;
+        if size > max_size {
+            return Err(Error::PayloadTooLarge(Limit::FileSize(max_size)));
+        }
 
-    assert!(n > 0); // file should never be empty
+        sha256.update(&bytes);
 
-    Ok(format!("{:x}", sha256.finalize()))
+        if head.len() < sniff::SNIFF_LEN {
+            let missing = sniff::SNIFF_LEN - head.len();
+            head.extend_from_slice(&bytes[..missing.min(bytes.len())]);
+        }
+
+        file.write_all(&bytes).await?;
+    }
+
+    file.flush().await?;
+
+    Ok((format!("{:x}", sha256.finalize()), size, head))
 }
 
-pub async fn try_move_to_uploads(from: &str, to: &str) -> Result<(), String> {
-    let target = util::prepend_upload_dir(to);
+/// Moves a temporary file of `size` bytes to the blob of checksum `to`.
+/// An existing blob of the same size is kept and the temporary file dropped,
+/// verifying its content is left to `gc --rehash`, which reads all of it.
+pub async fn try_move_to_uploads(
+    tmp_file: TmpFile,
+    to: &str,
+    size: u64,
+    storage: &Storage,
+) -> Result<(), Error> {
+    let target = storage.blob_path(to);
+
+    let existing = tokio::fs::metadata(&target).await.ok().map(|metadata| metadata.len());
+    match existing {
+        Some(existing) if existing == size => {
+            // keeps garbage collection from taking it for an orphan
+            let target = target.clone();
+            tokio::task::spawn_blocking(move || {
+                std::fs::File::options().write(true).open(&target)?.set_modified(SystemTime::now())
+            }).await.map_err(std::io::Error::other)??;
+            return Ok(());
+        },
+        Some(existing) => warn!("file {} has {} bytes instead of {}, replacing it",
+            to, existing, size),
+        None => (),
+    }
 
-    if std::fs::metadata(&target).is_ok() {
-        eprintln!("Warning: file {} exists, not overwriting", to);
-        return Ok(());
+    if let Some(parent) = Path::new(&target).parent() {
+        tokio::fs::create_dir_all(parent).await?;
     }
 
-    std::fs::rename(&from, &target)
-        .map_err(|e| {
-            format!("failed to rename file: {}: {} -> {}", e, from, target)
-        })?;
+    match tokio::fs::rename(tmp_file.path(), &target).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_into_place(tmp_file.path(), &target).await?
+        },
+        result => result?,
+    }
+
+    metrics::STORAGE_BYTES.add(tokio::fs::metadata(&target).await?.len());
+    match existing {
+        Some(size) => metrics::STORAGE_BYTES.sub(size),
+        None => metrics::STORAGE_BLOBS.add(1),
+    }
 
     Ok(())
 }
+
+/// Copies a file across filesystems, syncing the copy before renaming it
+/// to `target`, so that no partially written blob is ever visible.
+async fn copy_into_place(from: &str, target: &str) -> Result<(), Error> {
+    let partial = format!("{}.{}", target, util::new_random_uuid());
+
+    let result = async {
+        tokio::fs::copy(from, &partial).await?;
+        tokio::fs::File::open(&partial).await?.sync_all().await?;
+        tokio::fs::rename(&partial, target).await
+    }.await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+
+    Ok(result?)
+}
+
+/// Returns the hex encoded SHA-256 checksum of a file.
+pub async fn hash_file(path: &str) -> Result<String, Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+
+    let mut sha256 = sha2::Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", sha256.finalize()))
+}
+
+/// Returns the path, size and modification time of all files in a directory,
+/// including those in subdirectories if `recursive` is set.
+pub async fn list_dir(dir: &str, recursive: bool) -> Result<Vec<(String, u64, SystemTime)>, Error> {
+    let mut dirs = vec![dir.to_owned()];
+    let mut files = vec![];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let path = Path::new(&dir).join(entry.file_name()).to_string_lossy().into_owned();
+            if metadata.is_file() {
+                files.push((path, metadata.len(), metadata.modified()?));
+            } else if recursive && metadata.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Returns the number of blobs in `uploads/` and the bytes they take up.
+pub async fn upload_dir_usage(storage: &Storage) -> Result<(usize, u64), Error> {
+    let blobs = list_dir(storage.uploads_dir(), true).await?;
+
+    Ok((blobs.len(), blobs.iter().map(|(_, size, _)| size).sum()))
+}
+
+/// Removes a blob unless a short id refers to it again. It is moved aside
+/// before checking, so that an upload of the same content either references
+/// it in time to have it put back, or finds it gone and stores its own copy.
+/// Returns whether the blob was removed.
+pub async fn try_remove_from_uploads(
+    sha256: &str,
+    storage: &Storage,
+    db: &sled::Db,
+) -> Result<bool, Error> {
+    let target = storage.blob_path(sha256);
+    let aside = format!("{}.{}", target, util::new_random_uuid());
+
+    match tokio::fs::rename(&target, &aside).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        result => result?,
+    }
+
+    let referenced = match db::is_sha_referenced(db, sha256) {
+        Ok(referenced) => referenced,
+        Err(e) => {
+            tokio::fs::rename(&aside, &target).await?;
+            return Err(e);
+        },
+    };
+
+    if referenced {
+        info!("blob {} is referenced again, keeping it", sha256);
+        tokio::fs::rename(&aside, &target).await?;
+        return Ok(false);
+    }
+
+    let size = tokio::fs::metadata(&aside).await?.len();
+    tokio::fs::remove_file(&aside).await?;
+    metrics::STORAGE_BLOBS.sub(1);
+    metrics::STORAGE_BYTES.sub(size);
+
+    Ok(true)
+}
@@ -0,0 +1,204 @@
+use crate::db;
+use crate::error::Error;
+use crate::file::{self, Storage};
+use crate::metrics;
+use crate::util;
+
+use tracing::{error, info, warn};
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+/// Files younger than this may belong to uploads that are still in progress.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How long removed short ids keep answering 410 before they are forgotten.
+pub const TOMBSTONE_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+pub struct Options {
+    /// only report what would be removed
+    pub report_only:  bool,
+    /// hash every blob to detect corruption, which reads all of them
+    pub rehash:       bool,
+    pub grace_period: Duration,
+}
+
+/// What a pass found, and removed unless it only reported.
+#[derive(Default)]
+pub struct Report {
+    pub stale_tmp_files: Vec<String>,
+    pub orphan_blobs:    Vec<String>,
+    pub freed_bytes:     u64,
+    pub dangling_names:  usize,
+    /// marks of short ids removed longer than [`TOMBSTONE_RETENTION`] ago
+    pub tombstones:      usize,
+    /// short ids whose blob does not exist
+    pub missing_blobs:   Vec<(String, String)>,
+    /// blobs whose content does not match their checksum
+    pub corrupt_blobs:   Vec<String>,
+}
+
+impl Report {
+    pub fn log(&self, report_only: bool) {
+        let removed = match report_only {
+            true => "would remove",
+            false => "removed",
+        };
+
+        for path in &self.stale_tmp_files {
+            info!("{} stale temporary file {}", removed, path);
+        }
+        for path in &self.orphan_blobs {
+            info!("{} unreferenced blob {}", removed, path);
+        }
+        if self.dangling_names > 0 {
+            info!("{} {} dangling legacy filename entries", removed, self.dangling_names);
+        }
+        if self.tombstones > 0 {
+            info!("{} {} tombstone(s) of long removed short ids", removed, self.tombstones);
+        }
+        for (id, sha256) in &self.missing_blobs {
+            warn!("blob {} of short id {} is missing", sha256, id);
+        }
+        for sha256 in &self.corrupt_blobs {
+            warn!("blob {} does not match its checksum", sha256);
+        }
+
+        info!("gc {} {} file(s) taking up {} bytes, found {} missing and {} corrupt blob(s)",
+            removed,
+            self.stale_tmp_files.len() + self.orphan_blobs.len(),
+            self.freed_bytes,
+            self.missing_blobs.len(),
+            self.corrupt_blobs.len());
+    }
+}
+
+/// Periodically reconciles `tmp/` and `uploads/` with the database.
+pub fn spawn(db: sled::Db, storage: Storage, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately, startup already ran a pass
+        interval.tick().await;
+
+        let options = Options { report_only: false, rehash: false, grace_period: GRACE_PERIOD };
+
+        loop {
+            interval.tick().await;
+            match run(db.clone(), &storage, &options).await {
+                Ok(report) => report.log(false),
+                Err(e) => error!("garbage collection failed: {}", e),
+            }
+        }
+    })
+}
+
+pub async fn run(db: sled::Db, storage: &Storage, options: &Options) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    let now = SystemTime::now();
+    let is_stale = |modified: SystemTime| {
+        now.duration_since(modified).unwrap_or_default() >= options.grace_period
+    };
+
+    for (path, size, modified) in list_tmp_files(storage).await? {
+        if is_stale(modified) {
+            report.freed_bytes += size;
+            report.stale_tmp_files.push(path);
+        }
+    }
+
+    // read before listing blobs, so that blobs of new uploads are recent enough to be skipped
+    let id_shas = db::get_id_shas(db.clone()).await?;
+    let referenced: HashSet<&str> = id_shas.iter().map(|(_, sha256)| sha256.as_str()).collect();
+
+    let blobs = file::list_dir(storage.uploads_dir(), true).await?;
+
+    // corrects any drift of the counts kept up to date in between,
+    // removals below are counted as they happen
+    if !options.report_only {
+        metrics::STORAGE_BLOBS.set(blobs.len() as u64);
+        metrics::STORAGE_BYTES.set(blobs.iter().map(|(_, size, _)| size).sum());
+    }
+
+    let mut orphans = vec![];
+
+    for (path, size, modified) in blobs {
+        if !referenced.contains(file_name(&path)) && is_stale(modified) {
+            report.freed_bytes += size;
+            report.orphan_blobs.push(path.clone());
+            orphans.push((path, size));
+        }
+    }
+
+    for (id, sha256) in &id_shas {
+        if tokio::fs::metadata(storage.blob_path(sha256)).await.is_err() {
+            report.missing_blobs.push((id.clone(), sha256.clone()));
+        }
+    }
+
+    if options.rehash {
+        for sha256 in &referenced {
+            match file::hash_file(&storage.blob_path(sha256)).await {
+                Ok(actual) if actual != *sha256 => report.corrupt_blobs.push(sha256.to_string()),
+                // missing blobs were reported above
+                _ => (),
+            }
+        }
+    }
+
+    let before = util::unix_now().saturating_sub(TOMBSTONE_RETENTION.as_secs());
+    report.tombstones = db::remove_tombstones(db.clone(), before, options.report_only).await?;
+
+    // names are dropped along with the tombstones of their short ids
+    report.dangling_names = db::remove_dangling_names(db.clone(), options.report_only).await?;
+
+    if !options.report_only {
+        for path in &report.stale_tmp_files {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                error!("failed to remove {}: {}", path, e);
+            }
+        }
+        for (path, size) in &orphans {
+            let sha256 = file_name(path);
+            // an upload may have referenced a blob since the short ids were read
+            let result = match storage.blob_path(sha256) == *path {
+                true => file::try_remove_from_uploads(sha256, storage, &db).await.map(|_| ()),
+                false => tokio::fs::remove_file(path).await.map_err(Error::from).map(|()| {
+                    metrics::STORAGE_BLOBS.sub(1);
+                    metrics::STORAGE_BYTES.sub(*size);
+                }),
+            };
+            if let Err(e) = result {
+                error!("failed to remove {}: {}", path, e);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Removes all temporary files, once no upload is in progress anymore.
+pub async fn remove_tmp_files(storage: &Storage) -> Result<Vec<String>, Error> {
+    let mut removed = vec![];
+
+    for (path, _, _) in list_tmp_files(storage).await? {
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => removed.push(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => error!("failed to remove {}: {}", path, e),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// The temporary directory may be shared, only our own files are touched.
+async fn list_tmp_files(storage: &Storage) -> Result<Vec<(String, u64, SystemTime)>, Error> {
+    let mut files = file::list_dir(storage.tmp_dir(), false).await?;
+    files.retain(|(path, _, _)| util::is_uuid(file_name(path)));
+    Ok(files)
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or("")
+}
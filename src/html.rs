@@ -0,0 +1,128 @@
+use crate::error::Error;
+
+use hyper::body::Body;
+use warp::http::{Response, StatusCode};
+
+use std::fmt;
+
+// the rendered pages neither need scripts, styles nor any other resources
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+/// Markup that is safe to send, either written by us or escaped.
+pub struct Html(String);
+
+impl Html {
+    #[doc(hidden)]
+    pub fn __from_template(s: String) -> Html {
+        Html(s)
+    }
+
+    pub fn concat(parts: impl IntoIterator<Item = Html>) -> Html {
+        Html(parts.into_iter().map(|part| part.0).collect())
+    }
+}
+
+/// Values that can be interpolated into markup by [`html!`].
+pub trait Render {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl Render for Html {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Render for str {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self;
+        while let Some(i) = rest.find(['&', '<', '>', '"', '\'']) {
+            f.write_str(&rest[..i])?;
+            f.write_str(match rest.as_bytes()[i] {
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                b'"' => "&quot;",
+                _    => "&#x27;",
+            })?;
+            rest = &rest[i + 1..];
+        }
+        f.write_str(rest)
+    }
+}
+
+impl Render for String {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().render(f)
+    }
+}
+
+impl Render for u64 {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Render for usize {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl<T: Render + ?Sized> Render for &T {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).render(f)
+    }
+}
+
+#[doc(hidden)]
+pub struct Escaped<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Render + ?Sized> fmt::Display for Escaped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.render(f)
+    }
+}
+
+/// Formats markup like `format!`, escaping every argument that is not
+/// [`Html`] itself. The format string is the only markup taken verbatim.
+macro_rules! html {
+    ($template:literal $(, $arg:expr)* $(,)?) => {
+        $crate::html::Html::__from_template(
+            format!($template $(, $crate::html::Escaped(&$arg))*))
+    };
+}
+
+pub(crate) use html;
+
+pub fn response(status: StatusCode, page: Html) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Content-Security-Policy", CONTENT_SECURITY_POLICY)
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Body::from(page.0))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_arguments() {
+        let page = html!("<p>{}</p>", "<script>alert('x') & \"y\"</script>");
+        assert_eq!(page.0,
+            "<p>&lt;script&gt;alert(&#x27;x&#x27;) &amp; &quot;y&quot;&lt;/script&gt;</p>");
+
+        let link = html!("<a href=\"{}\">{}</a>", "\" onclick=\"x".to_owned(), "naïve <b>");
+        assert_eq!(link.0, "<a href=\"&quot; onclick=&quot;x\">naïve &lt;b&gt;</a>");
+    }
+
+    #[test]
+    fn keeps_markup_and_numbers() {
+        let items = Html::concat(vec![html!("<li>{}</li>", "a&b"), html!("<li>{}</li>", 7u64)]);
+        let list = html!("<ul>{}</ul>{}", items, 3usize);
+        assert_eq!(list.0, "<ul><li>a&amp;b</li><li>7</li></ul>3");
+    }
+}
@@ -1,76 +1,151 @@
-use crate::config::{Config, SuffixType};
-use crate::db;
-use crate::file;
+use crate::auth::{self, Access, Scope};
+use crate::config::{Config, LiveConfig, SuffixType};
+use crate::db::{self, Metadata, Uploader};
+use crate::error::{Error, Limit};
+use crate::file::{self, Storage};
+use crate::html::{self, html, Html};
+use crate::listen::Listener;
+use crate::listing::{Listing, StateQuery};
+use crate::logging::{self, LogFormat};
+use crate::metrics;
+use crate::range;
+use crate::sniff;
 use crate::util;
 
+use bytes::Bytes;
 use futures_core::{Future, Stream};
-use futures_util::TryStreamExt;
-use hyper::body::Body;
+use futures_util::{future, TryStreamExt};
+use hyper::body::{Body, HttpBody};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
 use mime::Mime;
 use mpart_async::server::MultipartStream;
-use tokio_stream::wrappers::UnixListenerStream;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tracing::{error, info_span, warn, Instrument};
 use warp::{Filter, Rejection};
-use warp::http::{Response, StatusCode};
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, HeaderValue, Method, Response, StatusCode};
 
-use std::io::prelude::*;
-use std::fs::{File, OpenOptions};
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+
+// both schemes are accepted, basic auth takes the key as password
+const WWW_AUTHENTICATE: &str = "Bearer, Basic realm=\"urlnao\"";
 
 pub fn create_server(
     db: sled::Db,
-    config: &Config,
-    incoming: UnixListenerStream
+    live_config: &LiveConfig,
+    listeners: Vec<Listener>,
+    draining: watch::Receiver<bool>,
 ) -> tokio::task::JoinHandle<()> {
+    // routes are fixed at startup, paths cannot be reloaded
+    let config = live_config.load();
+
     let db_up = db.clone();
-    let config_up = config.clone();
+    let config_up = live_config.clone();
+    let draining_up = draining.clone();
     let upload = warp::path("up")
         .and(warp::path::end())
         .and(warp::post())
-        // limit upload size
-        .and(warp::body::content_length_limit(500_000_000))
-        .and(warp::header::<Mime>("content-type"))
+        .and(warp::header::optional::<Mime>("content-type"))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::header::optional::<String>("x-urlnao-ttl"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<UploadQuery>())
+        .and(uploader_filter())
         .and(warp::body::stream())
-        .and_then(move |mime,body| {
-            handle_upload(mime, body, db_up.clone(), config_up.clone())
-        });
-
-    let too_large = warp::path("up")
-        .and(warp::path::end())
-        .and(warp::post())
-        .and(warp::header::<Mime>("content-type"))
-        .map(|_| {
-            Response::builder()
-                .status(StatusCode::PAYLOAD_TOO_LARGE)
-                .body("Payload too large\n")
+        .and_then(move |mime, length, ttl, accept, authorization, query, uploader, body| {
+            let draining = *draining_up.borrow();
+            respond(handle_upload(mime, length, ttl, accept, authorization, query, uploader,
+                body, draining, db_up.clone(), config_up.load_full()))
         });
 
     let db_id = db.clone();
-    let config_id = config.clone();
-    let download_id = warp::get()
-        .and(warp::path("f"))
+    let config_id = live_config.clone();
+    let download_id = warp::get().or(warp::head()).unify()
+        .and(path_filter(&config.shortid_path))
         .and(warp::path::param())
         .and(warp::path::end())
         .and_then(move |id| {
-            construct_response_for_id(id, config_id.clone(), db_id.clone())
+            metrics::DOWNLOADS.inc("shortid");
+            respond(construct_response_for_id(id, config_id.load_full(), db_id.clone()))
         });
 
-    let config_state = config.clone();
+    let config_state = live_config.clone();
     let db_state = db.clone();
     let state = warp::get()
         .and(warp::path("state"))
         .and(warp::path::end())
-        .and_then(move || {
-            construct_state_response(config_state.clone(), db_state.clone())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<StateQuery>())
+        .and_then(move |accept, authorization, query| {
+            respond(construct_state_response(
+                accept, authorization, query, config_state.load_full(), db_state.clone()))
         });
 
 
     let db_orig = db.clone();
-    let download_orig = warp::get()
-        .and(warp::path("d"))
+    let config_orig = live_config.clone();
+    let download_orig = warp::get().or(warp::head()).unify()
+        .and(warp::method())
+        .and(path_filter(&config.download_path))
+        .and(warp::path::param())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::header::headers_cloned())
+        .and_then(move |method, id, _filename, headers| {
+            metrics::DOWNLOADS.inc("download");
+            respond(construct_response_for_filename(
+                method, id, headers, config_orig.load_full(), db_orig.clone()))
+        });
+
+    let db_legacy = db.clone();
+    let config_legacy = live_config.clone();
+    let download_legacy = warp::get().or(warp::head()).unify()
+        .and(warp::method())
+        .and(path_filter(&config.download_path))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::header::headers_cloned())
+        .and_then(move |method, filename, headers| {
+            metrics::DOWNLOADS.inc("download");
+            respond(construct_response_for_legacy_filename(
+                method, filename, headers, config_legacy.load_full(), db_legacy.clone()))
+        });
+
+    let db_delete = db.clone();
+    let config_delete = live_config.clone();
+    let delete = warp::delete()
+        .and(path_filter(&config.shortid_path))
         .and(warp::path::param())
         .and(warp::path::end())
-        .and_then(move |filename| {
-            construct_response_for_filename(filename, db_orig.clone())
+        .and(warp::header::optional::<String>("x-urlnao-deletion-token"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |id, token, authorization| {
+            respond(handle_delete(id, token, authorization, config_delete.load_full(), db_delete.clone()))
+        });
+
+    // for clients that can only follow links
+    let db_delete_link = db.clone();
+    let config_delete_link = live_config.clone();
+    let delete_link = warp::get()
+        .and(warp::path("del"))
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and_then(move |id, token| {
+            respond(handle_delete(
+                id, Some(token), None, config_delete_link.load_full(), db_delete_link.clone()))
         });
 
     let landing_page = warp::get()
@@ -89,263 +164,1034 @@ pub fn create_server(
                 .body("Bad Request\n")
         });
 
-    let routes = landing_page
-        .or(download_id)
-        .or(download_orig)
-        .or(upload)
-        .or(too_large)
-        .or(state)
-        .or(reject);
+    let routes = path_filter(&config.base_path)
+        .and(landing_page
+            .or(download_id)
+            .or(download_orig)
+            .or(download_legacy)
+            .or(delete)
+            .or(delete_link)
+            .or(upload)
+            .or(state))
+        .or(reject)
+        .map(warp::Reply::into_response)
+        .boxed();
 
+    let servers = listeners.into_iter().map(|listener| {
+        let routes = routes.clone();
+        let live_config = live_config.clone();
+        let mut draining = draining.clone();
+        // stops accepting connections and waits for requests in progress
+        let shutdown = async move {
+            while !*draining.borrow() {
+                if draining.changed().await.is_err() {
+                    break
+                }
+            }
+        };
+        tokio::spawn(async move {
+            let result = match listener {
+                Listener::Unix(listener) => serve(UnixListenerStream::new(listener),
+                    |_| None, routes, live_config, shutdown).await,
+                Listener::Tcp(listener) => serve(TcpListenerStream::new(listener),
+                    |conn| conn.peer_addr().ok(), routes, live_config, shutdown).await,
+            };
+            if let Err(e) = result {
+                error!("server error: {}", e);
+            }
+        })
+    });
 
+    let mut servers: Vec<_> = servers.collect();
     tokio::spawn(async move {
-        warp::serve(routes).run_incoming(incoming).await;
+        while !servers.is_empty() {
+            let (result, _, rest) = future::select_all(servers).await;
+            if let Err(e) = result {
+                error!("server failed: {}", e);
+            }
+            // outside of shutdowns, a server only stops if it panicked,
+            // which stops the whole service
+            if !*draining.borrow() {
+                break
+            }
+            servers = rest;
+        }
     })
 }
 
-pub async fn construct_state_response(
-    config: Config,
-    db: sled::Db
-) -> Result<http::Response<String>, Rejection> {
-    let mut response = vec![];
+/// Serves `/metrics` on its own listener, which is not meant to be public.
+pub fn create_metrics_server(listener: Listener) -> tokio::task::JoinHandle<()> {
+    let route = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and_then(|| respond(construct_metrics_response()));
 
-    response.push("<!doctype html>\n\
-                   <head>\n\
-                     <meta charset=\"utf-8\">\n\
-                     <title>Urlnao</title>\n\
-                   </head>\n\
-                   <body>".to_owned());
+    tokio::spawn(async move {
+        match listener {
+            Listener::Unix(listener) => warp::serve(route)
+                .run_incoming(UnixListenerStream::new(listener)).await,
+            Listener::Tcp(listener) => warp::serve(route)
+                .run_incoming(TcpListenerStream::new(listener)).await,
+        }
+    })
+}
+
+async fn construct_metrics_response() -> Result<http::Response<Body>, Error> {
+    let body = metrics::render();
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .body(Body::from(body))?)
+}
+
+type Routes = BoxedFilter<(Response<Body>,)>;
+
+/// Serves the connections of a listener until `shutdown` completes.
+async fn serve<S, C>(
+    incoming: S,
+    peer_addr: fn(&C) -> Option<SocketAddr>,
+    routes: Routes,
+    live_config: LiveConfig,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()>
+where
+    S: Stream<Item = io::Result<C>> + Send,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let make_service = make_service_fn(move |conn: &C| {
+        let peer = peer_addr(conn);
+        let routes = routes.clone();
+        let live_config = live_config.clone();
+        future::ok::<_, Infallible>(service_fn(move |request| {
+            handle_request(request, peer, routes.clone(), live_config.load().log_format)
+        }))
+    });
+
+    hyper::Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// The client of a request as far as it can be trusted, which is attached
+/// to the request for the routes to record.
+#[derive(Clone, Default)]
+struct Client {
+    address: Option<String>,
+    user:    Option<String>,
+}
+
+/// Tags a request with an ID, which is echoed in `X-Request-Id` and attached
+/// to everything logged while handling it, and writes its access log line.
+async fn handle_request(
+    mut request: http::Request<Body>,
+    peer: Option<SocketAddr>,
+    routes: Routes,
+    log_format: LogFormat,
+) -> Result<http::Response<Body>, Infallible> {
+    let started = Instant::now();
+    let time = SystemTime::now();
+
+    let header = |name| request.headers().get(name)
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .map(str::to_owned);
 
-    let entries = match db::get_all_ids_and_names(db).await {
-        Ok(e) => e,
-        Err(_) => return Err(warp::reject::not_found()),
+    // a proxy's ID is kept, so that its logs and ours can be matched up
+    let id = header("x-request-id")
+        .filter(|id| is_valid_request_id(id))
+        .unwrap_or_else(util::new_random_uuid);
+    // clients can send X-Forwarded-For and X-Remote-User themselves, only the
+    // entry appended and the user set by the proxy in front of the Unix socket
+    // can be trusted
+    let client = match peer {
+        Some(peer) => Client { address: Some(peer.ip().to_string()), user: None },
+        None => Client {
+            address: header("x-forwarded-for")
+                .and_then(|addrs| addrs.rsplit(',').next().map(|addr| addr.trim().to_owned())),
+            user: header("x-remote-user"),
+        },
     };
+    let referer = header("referer");
+    let user_agent = header("user-agent");
+    let method = request.method().clone();
+    let uri = request.uri().path_and_query()
+        .map_or_else(|| request.uri().path().to_owned(), ToString::to_string);
+    let version = request.version();
 
-    response.push(format!("<p>Urlnao currently has {} upload(s):</p>\n<ul>", entries.len()));
-
-    for upload in entries {
-        match upload.orig_name {
-            Some(orig_name) => response.push(format!(
-                    "<pre><li><a href=\"{}\">checksum: {} (filename: {})</a></li></pre>",
-                    config.prepend_url(SuffixType::ShortID, &upload.id),
-                    upload.checksum,
-                    orig_name)),
-            None => response.push(format!(
-                    "<pre><li><a href=\"{}\">checksum: {} (no filename)</a></li></pre>",
-                    config.prepend_url(SuffixType::ShortID, &upload.id),
-                    upload.checksum)),
-        }
+    request.extensions_mut().insert(client.clone());
+
+    let mut response = match warp::service(routes).call(request).instrument(info_span!("request", request_id = %id)).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+
+    let bytes = response.headers().get("content-length")
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .unwrap_or_else(|| HttpBody::size_hint(response.body()).lower());
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("X-Request-Id", value);
     }
 
-    response.push("</ul>\n</body>\n".to_owned());
+    logging::access(&logging::Access {
+        id:         &id,
+        remote:     client.address.as_deref(),
+        user:       client.user.as_deref(),
+        time,
+        method:     method.as_str(),
+        uri:        &uri,
+        version:    &format!("{:?}", version),
+        status:     response.status().as_u16(),
+        bytes,
+        referer:    referer.as_deref(),
+        user_agent: user_agent.as_deref(),
+        duration:   started.elapsed(),
+    }, log_format);
 
-    match Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "text/html")
-        .body(response.join("\n")) {
-            Err(_) => Err(warp::reject::not_found()),
-            Ok(response) => Ok(response),
+    Ok(response)
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+async fn respond(
+    response: impl Future<Output = Result<http::Response<Body>, Error>>
+) -> Result<http::Response<Body>, Rejection> {
+    Ok(response.await.unwrap_or_else(error_response))
+}
+
+fn error_response(e: Error) -> http::Response<Body> {
+    if e.status().is_server_error() {
+        error!("{}", e);
     }
+    // reasons may quote the request, keep browsers from rendering them as HTML
+    let mut response = Response::new(Body::from(format!("{}\n", e.reason())));
+    *response.status_mut() = e.status();
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", HeaderValue::from_static("text/plain; charset=utf-8"));
+    headers.insert("X-Content-Type-Options", HeaderValue::from_static("nosniff"));
+    if let Error::Unauthorized = e {
+        headers.insert("WWW-Authenticate", HeaderValue::from_static(WWW_AUTHENTICATE));
+    }
+    response
+}
+
+/// Extracts the identity of the client that [`handle_request`] trusts.
+fn uploader_filter() -> BoxedFilter<(Uploader,)> {
+    warp::ext::optional::<Client>()
+        .map(|client: Option<Client>| {
+            let client = client.unwrap_or_default();
+            Uploader { forwarded_for: client.address, remote_user: client.user, api_key: None }
+        })
+        .boxed()
+}
+
+/// Matches a path made up of any number of segments, e.g. `files/s`.
+fn path_filter(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+pub async fn construct_state_response(
+    accept: Option<String>,
+    authorization: Option<String>,
+    query: StateQuery,
+    config: Arc<Config>,
+    db: sled::Db
+) -> Result<http::Response<Body>, Error> {
+    let access = auth::authorize(authorization.as_deref(), Scope::List, &config, &db).await?;
+
+    let listing = Listing::from_query(&query, access.owner())?;
+
+    let page = listing.fetch(db.clone()).await?;
+
+    let (total_uploads, total_bytes) = db::get_totals(db, access.owner()).await?;
+
+    if wants_json(accept.as_deref(), query.format.as_deref()) {
+        let uploads: Vec<JsonStateEntry> = page.uploads.iter()
+            .map(|upload| JsonStateEntry {
+                id:           &upload.id,
+                url:          config.prepend_url(SuffixType::ShortID, &upload.id),
+                download_url: upload.metadata.filename.as_ref().map(|orig| config.prepend_url(
+                    SuffixType::FileName,
+                    &format!("{}/{}", upload.id, util::encode_path_segment(orig)))),
+                field:        upload.metadata.field_name.as_deref(),
+                filename:     upload.metadata.filename.as_deref(),
+                sha256:       &upload.checksum,
+                size:         upload.metadata.size,
+                mime_type:    &upload.metadata.mime_type,
+                uploaded_at:  upload.metadata.uploaded_at,
+                uploader:     &upload.metadata.uploader,
+            })
+            .collect();
+
+        let state = JsonState {
+            summary: JsonSummary { total_uploads, total_bytes },
+            uploads,
+            next_cursor: page.next_cursor,
+        };
+
+        let body = serde_json::to_string_pretty(&state)
+            .map_err(|e| Error::Io(e.into()))?;
+
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!("{}\n", body)))?);
+    }
+
+    let items = page.uploads.iter().map(|upload| {
+        let url = config.prepend_url(SuffixType::ShortID, &upload.id);
+        let size = upload.metadata.size.map_or("unknown size".to_owned(), |size| format!("{} bytes", size));
+        match &upload.metadata.filename {
+            Some(orig_name) => html!(
+                "<li><pre><a href=\"{}\">checksum: {} (filename: {}, {})</a></pre></li>\n",
+                url, upload.checksum, orig_name, size),
+            None => html!(
+                "<li><pre><a href=\"{}\">checksum: {} (no filename, {})</a></pre></li>\n",
+                url, upload.checksum, size),
+        }
+    });
+
+    let next_page = match page.next_cursor {
+        Some(cursor) => html!("<p><a href=\"{}\">next page</a></p>\n", query.with_cursor(&cursor)),
+        None => html!(""),
+    };
+
+    let page = html!("<!doctype html>\n\
+                      <html>\n\
+                      <head>\n\
+                        <meta charset=\"utf-8\">\n\
+                        <title>Urlnao</title>\n\
+                      </head>\n\
+                      <body>\n\
+                      <p>Urlnao currently has {} upload(s) taking up {} byte(s):</p>\n\
+                      <ul>\n{}</ul>\n\
+                      {}\
+                      </body>\n\
+                      </html>\n",
+                     total_uploads, total_bytes, Html::concat(items), next_page);
+
+    html::response(StatusCode::OK, page)
 }
 
 pub async fn construct_response_for_id(
     short_id: String,
-    config: Config,
+    config: Arc<Config>,
     db: sled::Db
-) -> Result<http::Response<Body>, Rejection> {
-    let (_, orig) = match db::try_get_sha_and_orig(db, short_id.as_bytes()).await {
-        Ok(t)  => t,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return Err(warp::reject::not_found());
+) -> Result<http::Response<Body>, Error> {
+    if db::is_gone(db.clone(), short_id.as_bytes()).await? {
+        return gone_response();
+    }
+    let (_, metadata) = db::try_get_sha_and_metadata(db, short_id.as_bytes()).await?;
+    let orig = metadata.filename
+        .ok_or_else(|| Error::Corrupted("short id without original filename".to_string()))?;
+    let response = Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header("Location", config.prepend_url(SuffixType::FileName,
+            &format!("{}/{}", short_id, util::encode_path_segment(&orig))))
+        .body(Body::empty())?;
+    Ok(response)
+}
+
+/// Answers requests for short ids that were deleted or have expired.
+fn gone_response() -> Result<http::Response<Body>, Error> {
+    let response = Response::builder()
+        .status(StatusCode::GONE)
+        .body(Body::from("Gone\n"))?;
+    Ok(response)
+}
+
+/// Deletes an upload given one of its deletion tokens or, without a token,
+/// an API key that may delete it.
+pub async fn handle_delete(
+    short_id: String,
+    token: Option<String>,
+    authorization: Option<String>,
+    config: Arc<Config>,
+    db: sled::Db
+) -> Result<http::Response<Body>, Error> {
+    let token_hash = token.as_deref().map(util::hash_token);
+    let access;
+    let proof = match &token_hash {
+        Some(token_hash) => db::Proof::Token(token_hash),
+        None => {
+            access = auth::authorize(authorization.as_deref(), Scope::Delete, &config, &db).await?;
+            match (&access, access.owner()) {
+                // without API keys, there is nothing but the token to go by
+                (Access::Anonymous, _) => return Err(Error::MissingDeletionToken),
+                (Access::Key(_), Some(owner)) => db::Proof::Owner(owner),
+                (Access::Key(_), None) => db::Proof::Admin,
+            }
         },
     };
-    let response = match Response::builder()
-                    .status(StatusCode::MOVED_PERMANENTLY)
-                    .header("Location", config.prepend_url(SuffixType::FileName, &orig))
-                    .body(Body::empty()) {
-        Ok(r) => r,
-        Err(_) => return Err(warp::reject::not_found()),
+    let deletion = db::try_delete(db.clone(), short_id.as_bytes(), proof).await?;
+    let (status, body) = match deletion {
+        db::Deletion::Deleted(unreferenced) => {
+            if let Some(sha256) = unreferenced {
+                if let Err(e) = file::try_remove_from_uploads(&sha256, &config.storage, &db).await {
+                    error!("{}", e);
+                }
+            }
+            (StatusCode::OK, "Deleted\n")
+        },
+        db::Deletion::Denied => (StatusCode::FORBIDDEN, "Forbidden\n"),
+        db::Deletion::UnknownId => match db::is_gone(db, short_id.as_bytes()).await? {
+            true => (StatusCode::GONE, "Gone\n"),
+            false => return Err(Error::NotFound),
+        },
     };
+    let response = Response::builder()
+        .status(status)
+        .body(Body::from(body))?;
     Ok(response)
 }
 
+/// Serves `/d/<short-id>/<name>`, where the name is only informational.
 pub async fn construct_response_for_filename(
+    method: Method,
+    short_id: String,
+    headers: HeaderMap,
+    config: Arc<Config>,
+    db: sled::Db
+) -> Result<http::Response<Body>, Error> {
+    if db::is_gone(db.clone(), short_id.as_bytes()).await? {
+        return gone_response();
+    }
+    let (sha256, metadata) = db::try_get_sha_and_metadata(db, short_id.as_bytes()).await?;
+    let filename = metadata.filename
+        .ok_or_else(|| Error::Corrupted("short id without original filename".to_string()))?;
+    construct_file_response(method, sha256, filename, &metadata.mime_type, headers, &config).await
+}
+
+/// Serves `/d/<name>` links created before names were stored per short id,
+/// like the download path of the upload the name was last given to.
+pub async fn construct_response_for_legacy_filename(
+    method: Method,
     filename: String,
+    headers: HeaderMap,
+    config: Arc<Config>,
     db: sled::Db
-) -> Result<http::Response<Vec<u8>>, Rejection> {
-    let sha256 = match db::try_get_sha_for_orig(db, filename.as_bytes()).await {
-        Ok(t)  => t,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return Err(warp::reject::not_found());
-        },
+) -> Result<http::Response<Body>, Error> {
+    let short_id = db::try_get_id_for_orig(db.clone(), filename.as_bytes()).await?;
+    construct_response_for_filename(method, short_id, headers, config, db).await
+}
+
+async fn construct_file_response(
+    method: Method,
+    sha256: String,
+    filename: String,
+    content_type: &str,
+    headers: HeaderMap,
+    config: &Config,
+) -> Result<http::Response<Body>, Error> {
+    let file = tokio::fs::File::open(config.storage.blob_path(&sha256)).await?;
+    let len = file.metadata().await?.len();
+
+    let disposition = match config.allows_inline(content_type) {
+        true => "inline",
+        false => "attachment",
     };
-    let mut file = match File::open(format!("uploads/{}", sha256)) {
-        Ok(file) => file,
-        Err(_) => {
-            eprintln!("Error: file not found");
-            return Err(warp::reject::not_found());
+    let content_disposition = util::content_disposition(disposition, &filename);
+
+    // uploads are stored by their checksum, so it makes for a strong ETag
+    let etag = format!("\"{}\"", sha256);
+
+    // the detected type is final, browsers must not second-guess it
+    let builder = Response::builder()
+        .header("Accept-Ranges", "bytes")
+        .header("ETag", &etag)
+        .header("X-Content-Type-Options", "nosniff");
+
+    if let Some(if_none_match) = header_str(&headers, "if-none-match") {
+        if etag_matches(if_none_match, &etag) {
+            let response = builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())?;
+            return Ok(response);
+        }
+    }
+
+    let builder = builder
+        .header("Content-Disposition", content_disposition);
+
+    // a Range header is only honoured if the If-Range validator still matches,
+    // dates are never considered a match as no Last-Modified is sent
+    let range_request = match header_str(&headers, "range") {
+        Some(range) => match header_str(&headers, "if-range") {
+            Some(if_range) if if_range.trim() != etag => range::RangeRequest::Full,
+            _ => range::parse(range, len),
         },
+        None => range::RangeRequest::Full,
     };
 
-    let split = &filename.split(".").collect::<Vec<&str>>();
-    let ext = split.last();
-    let content_type = match *ext.unwrap() {
-        "bmp"  => "image/bmp",
-        "gif"  => "image/gif",
-        "jpeg" |
-        "jpg"  => "image/jpeg",
-        "json" => "application/json",
-        "mp3"  => "audio/mpeg",
-        "mp4"  => "video/mp4",
-        "mpeg" => "video/mpeg",
-        "pdf"  => "application/pdf",
-        "png"  => "image/png",
-        "svg"  => "image/svg+xml",
-        "txt"  => "text/plain",
-        "webm" => "video/webm",
-        "webp" => "image/webp",
-        _      => "application/octet-stream",
-    };
-    let content_disposition = match content_type {
-        "application/json" |
-        "application/octet-stream" |
-        "application/pdf" => {
-            format!("attachment; filename={}", filename)
+    let (builder, segments, suffix) = match range_request {
+        range::RangeRequest::Full => (
+            builder
+                .status(StatusCode::OK)
+                .header("Content-Type", content_type)
+                .header("Content-Length", len),
+            vec![file::Segment { prefix: Bytes::new(), start: 0, len }],
+            Bytes::new(),
+        ),
+        range::RangeRequest::Unsatisfiable => {
+            let response = builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", len))
+                .body(Body::empty())?;
+            return Ok(response);
+        },
+        range::RangeRequest::Partial(ranges) if ranges.len() == 1 => (
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Type", content_type)
+                .header("Content-Range", ranges[0].content_range(len))
+                .header("Content-Length", ranges[0].len()),
+            vec![file::Segment { prefix: Bytes::new(), start: ranges[0].start, len: ranges[0].len() }],
+            Bytes::new(),
+        ),
+        range::RangeRequest::Partial(ranges) => {
+            let boundary = util::new_random_uuid();
+            let segments: Vec<file::Segment> = ranges.iter()
+                .map(|r| file::Segment {
+                    prefix: Bytes::from(format!(
+                        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                        boundary, content_type, r.content_range(len))),
+                    start: r.start,
+                    len: r.len(),
+                })
+                .collect();
+            let suffix = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+            let content_length = suffix.len() as u64 + segments.iter()
+                .map(|s| s.prefix.len() as u64 + s.len)
+                .sum::<u64>();
+            (
+                builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("Content-Type",
+                        format!("multipart/byteranges; boundary={}", boundary))
+                    .header("Content-Length", content_length),
+                segments,
+                suffix,
+            )
         },
-        _ => "inline".to_string(),
     };
 
-    let mut data = vec![];
-    let response = match file.read_to_end(&mut data) {
-        Ok(_) => {
-            match Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Content-Type", content_type)
-                    .header("Content-Disposition", content_disposition)
-                    .body(data) {
-                Ok(response) => response,
-                Err(_) => {
-                    eprintln!("failed to build response");
-                    return Err(warp::reject::not_found());
-                },
-            }
-        },
-        Err(_) => {
-            eprintln!("failed to read file");
-            return Err(warp::reject::not_found())
-        }
+    let body = match method {
+        Method::HEAD => Body::empty(),
+        _ => file::stream_segments(file, segments, suffix),
     };
 
-    Ok(response)
+    Ok(builder.body(body)?)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Implements the weak comparison used for If-None-Match.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+}
+
+/// The result of storing a single file of an upload request.
+pub struct UploadOutcome {
+    field_name:        Arc<str>,
+    original_filename: Arc<str>,
+    sha256:            Arc<str>,
+    size:              u64,
+    result:            Result<StoredUpload, Error>,
+}
+
+pub struct StoredUpload {
+    short_id: String,
+    token:    String,
+    expiry:   Option<u64>,
+    metadata: Metadata,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonUploadEntry<'a> {
+    Stored {
+        field:          &'a str,
+        filename:       &'a str,
+        url:            String,
+        download_url:   String,
+        size:           u64,
+        sha256:         &'a str,
+        mime_type:      &'a str,
+        deletion_token: &'a str,
+        deletion_url:   String,
+        expires_at:     Option<u64>,
+        uploaded_at:    Option<u64>,
+        uploader:       &'a Uploader,
+    },
+    Failed {
+        field:    &'a str,
+        filename: &'a str,
+        error:    String,
+        status:   u16,
+    },
+}
+
+#[derive(Serialize)]
+struct JsonState<'a> {
+    summary:     JsonSummary,
+    uploads:     Vec<JsonStateEntry<'a>>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonSummary {
+    total_uploads: u64,
+    total_bytes:   u64,
+}
+
+#[derive(Serialize)]
+struct JsonStateEntry<'a> {
+    id:           &'a str,
+    url:          String,
+    download_url: Option<String>,
+    field:        Option<&'a str>,
+    filename:     Option<&'a str>,
+    sha256:       &'a str,
+    size:         Option<u64>,
+    mime_type:    &'a str,
+    uploaded_at:  Option<u64>,
+    uploader:     &'a Uploader,
 }
 
 pub async fn create_upload_tasks(
     new_files: Vec<file::FileInfo>,
+    expiry: Option<u64>,
+    uploader: Uploader,
+    storage: &Storage,
     db: sled::Db
-) -> Vec<impl Future<Output = Option<String>>> {
+) -> Vec<impl Future<Output = UploadOutcome>> {
     let mut tasks = vec![];
 
     for file_info in new_files {
-        let orig_name = file_info.original_filename;
-        let name = util::prepend_tmp_dir(&file_info.uuid);
         let db = db.clone();
+        let uploader = uploader.clone();
+        let storage = storage.clone();
         tasks.push(futures::future::lazy(|_| async move {
-            let checksum = file::get_sha256_of_file(&name);
+            let field_name = file_info.field_name.clone();
+            let original_filename = file_info.original_filename.clone();
+            let sha256 = file_info.sha256.clone();
+            let size = file_info.size;
+            let result = store_upload(file_info, expiry, uploader, &storage, db).await;
+            UploadOutcome { field_name, original_filename, sha256, size, result }
+        }).await);
+    }
+
+    tasks
+}
+
+async fn store_upload(
+    file_info: file::FileInfo,
+    expiry: Option<u64>,
+    uploader: Uploader,
+    storage: &Storage,
+    db: sled::Db
+) -> Result<StoredUpload, Error> {
+    if file_info.size == 0 {
+        return Err(Error::EmptyFile);
+    }
+    let token = util::new_deletion_token();
+    let token_hash = util::hash_token(&token);
+    let metadata = Metadata::new(
+        &file_info.original_filename,
+        &file_info.field_name,
+        file_info.size,
+        &file_info.mime_type,
+        uploader,
+    );
+    // the blob is referenced before it is moved into place, so that a
+    // concurrent removal of the same content keeps or restores it
+    let short_id = db::try_get_new_shortid(
+        db.clone(), &file_info.sha256, &metadata, expiry, &token_hash).await?;
+    let moved = file::try_move_to_uploads(
+        file_info.tmp_file, &file_info.sha256, file_info.size, storage).await;
+    if let Err(e) = moved {
+        let deletion = db::try_delete(db.clone(), short_id.as_bytes(), db::Proof::Admin).await;
+        if let Ok(db::Deletion::Deleted(Some(sha256))) = deletion {
+            let _ = file::try_remove_from_uploads(&sha256, storage, &db).await;
+        }
+        return Err(e);
+    }
+    Ok(StoredUpload { short_id, token, expiry, metadata })
+}
 
-            let sha256 = match checksum.await {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Error: {}", e);
-                    return None;
+/// Clients may ask for JSON either with an Accept header or `?format=json`.
+fn wants_json(accept: Option<&str>, format: Option<&str>) -> bool {
+    if let Some(format) = format {
+        return format == "json";
+    }
+    accept.is_some_and(|accept| accept.split(',')
+        .filter_map(|media_range| media_range.split(';').next())
+        .any(|media_type| media_type.trim() == "application/json"))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_upload(
+    mime: Option<Mime>,
+    content_length: Option<u64>,
+    ttl_header: Option<String>,
+    accept: Option<String>,
+    authorization: Option<String>,
+    query: UploadQuery,
+    uploader: Uploader,
+    body: impl Stream<Item = Result<impl bytes::Buf, warp::Error>> + Unpin,
+    draining: bool,
+    db: sled::Db,
+    config: Arc<Config>,
+) -> Result<http::Response<Body>, Error> {
+    let json = wants_json(accept.as_deref(), query.format.as_deref());
+
+    let started = Instant::now();
+
+    let received = match draining {
+        true => Err(Error::ShuttingDown),
+        false => receive_upload(
+            mime, content_length, ttl_header, authorization, uploader, body, db, &config).await,
+    };
+
+    metrics::UPLOAD_DURATION.observe(started.elapsed().as_secs_f64());
+    match &received {
+        Ok(outcomes) => for outcome in outcomes {
+            match &outcome.result {
+                Ok(_) => {
+                    metrics::UPLOADS.inc();
+                    metrics::UPLOAD_SIZE.observe(outcome.size as f64);
                 },
-            };
-            if let Err(e) = file::try_move_to_uploads(&name, &sha256).await {
-                eprintln!("Error: failed to rename file: {}", e);
-                return None;
+                Err(e) => metrics::UPLOAD_FAILURES.inc(e.kind()),
             }
-            let short_id = match db::try_get_new_shortid(db.clone(), &sha256).await {
-                Ok(s) => s,
-                Err(_) => return None,
-            };
-            if let Err(e) = db::try_add_sha_orig(db, &sha256, &orig_name).await {
-                eprintln!("Error: {}", e);
-                return None;
+        },
+        Err(e) => metrics::UPLOAD_FAILURES.inc(e.kind()),
+    }
+
+    let outcomes = match received {
+        Ok(outcomes) => outcomes,
+        Err(e) if json => {
+            if e.status().is_server_error() {
+                error!("{}", e);
             }
-            Some(short_id)
-        }).await);
+            let body = serde_json::json!({ "error": e.reason(), "status": e.status().as_u16() });
+            let mut builder = Response::builder()
+                .status(e.status())
+                .header("Content-Type", "application/json");
+            if let Error::Unauthorized = e {
+                builder = builder.header("WWW-Authenticate", WWW_AUTHENTICATE);
+            }
+            return Ok(builder.body(Body::from(format!("{}\n", body)))?);
+        },
+        Err(e) => return Err(e),
+    };
+
+    for outcome in &outcomes {
+        if let Err(e) = &outcome.result {
+            if e.status().is_server_error() {
+                error!("{}", e);
+            }
+        }
     }
 
-    tasks
+    // only fail the whole request if not a single file could be stored
+    let status = match outcomes.iter().any(|o| o.result.is_ok()) {
+        true => StatusCode::OK,
+        false => outcomes.iter()
+            .find_map(|o| o.result.as_ref().err())
+            .map_or(StatusCode::OK, Error::status),
+    };
+
+    match json {
+        true => render_json_upload_response(status, &outcomes, &config),
+        false => render_text_upload_response(status, &outcomes, &config),
+    }
 }
 
-pub async fn handle_upload(
-    mime: Mime,
+fn render_text_upload_response(
+    status: StatusCode,
+    outcomes: &[UploadOutcome],
+    config: &Config,
+) -> Result<http::Response<Body>, Error> {
+    // deletion URLs are sent as headers to keep the body one URL per line
+    let mut builder = Response::builder()
+        .status(status);
+    let mut response = vec![];
+    for outcome in outcomes {
+        match &outcome.result {
+            Ok(stored) => {
+                response.push(config.prepend_url(SuffixType::ShortID, &stored.short_id));
+                builder = builder.header("X-Urlnao-Deletion-Url", config.prepend_url(
+                        SuffixType::Deletion, &format!("{}/{}", stored.short_id, stored.token)));
+            },
+            Err(e) => response.push(format!("upload failed: {}", e.reason())),
+        }
+    }
+
+    Ok(builder.body(Body::from(format!("{}\n", response.join("\n"))))?)
+}
+
+fn render_json_upload_response(
+    status: StatusCode,
+    outcomes: &[UploadOutcome],
+    config: &Config,
+) -> Result<http::Response<Body>, Error> {
+    let entries: Vec<JsonUploadEntry> = outcomes.iter()
+        .map(|outcome| match &outcome.result {
+            Ok(stored) => JsonUploadEntry::Stored {
+                field:          &outcome.field_name,
+                filename:       &outcome.original_filename,
+                url:            config.prepend_url(SuffixType::ShortID, &stored.short_id),
+                download_url:   config.prepend_url(SuffixType::FileName, &format!("{}/{}",
+                    stored.short_id, util::encode_path_segment(&outcome.original_filename))),
+                size:           outcome.size,
+                sha256:         &outcome.sha256,
+                mime_type:      &stored.metadata.mime_type,
+                deletion_token: &stored.token,
+                deletion_url:   config.prepend_url(SuffixType::Deletion,
+                    &format!("{}/{}", stored.short_id, stored.token)),
+                expires_at:     stored.expiry,
+                uploaded_at:    stored.metadata.uploaded_at,
+                uploader:       &stored.metadata.uploader,
+            },
+            Err(e) => JsonUploadEntry::Failed {
+                field:    &outcome.field_name,
+                filename: &outcome.original_filename,
+                error:    e.reason(),
+                status:   e.status().as_u16(),
+            },
+        })
+        .collect();
+
+    let body = serde_json::to_string_pretty(&entries)
+        .map_err(|e| Error::Io(e.into()))?;
+
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(format!("{}\n", body)))?)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_upload(
+    mime: Option<Mime>,
+    content_length: Option<u64>,
+    ttl_header: Option<String>,
+    authorization: Option<String>,
+    mut uploader: Uploader,
     body: impl Stream<Item = Result<impl bytes::Buf, warp::Error>> + Unpin,
     db: sled::Db,
-    config: Config,
-) -> Result<impl warp::Reply, Rejection> {
+    config: &Config,
+) -> Result<Vec<UploadOutcome>, Error> {
+    let access = auth::authorize(authorization.as_deref(), Scope::Upload, config, &db).await?;
+    uploader.api_key = access.key_name().map(str::to_owned);
+
+    let mime = match mime {
+        Some(mime) if mime.essence_str() == "multipart/form-data" => mime,
+        _ => return Err(Error::UnsupportedMediaType),
+    };
+
+    // limit upload size upfront if possible, chunked uploads are
+    // limited while streaming below
+    let max_upload_size = config.max_upload_size;
+    if let Some(length) = content_length {
+        if length > max_upload_size {
+            return Err(Error::PayloadTooLarge(Limit::UploadSize(max_upload_size)));
+        }
+    }
+
+    let mut ttl = config.clamp_ttl(config.default_ttl);
+    if let Some(value) = ttl_header {
+        ttl = config.clamp_ttl(util::parse_ttl(&value).map_err(Error::InvalidTtl)?);
+    }
+
     let boundary = mime
         .get_param("boundary")
         .map(|v| v.to_string())
-        .ok_or_else(|| {
-            warp::reject::not_found()
-        })?;
+        .ok_or(Error::MissingBoundary)?;
 
-    let mut parts = MultipartStream::new(
-        boundary,
-        body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())),
-    );
+    let received = Arc::new(AtomicU64::new(0));
+    let received_body = received.clone();
+    let body = body
+        .map_err(io::Error::other)
+        .and_then(move |mut buf| {
+            let bytes = buf.copy_to_bytes(buf.remaining());
+            let total = received_body.fetch_add(bytes.len() as u64, Ordering::Relaxed)
+                + bytes.len() as u64;
+            match total > max_upload_size {
+                true => future::ready(Err(io::Error::other("maximum upload size exceeded"))),
+                false => future::ready(Ok(bytes)),
+            }
+        });
+
+    let mut parts = MultipartStream::new(boundary, body);
 
-    let mut file_index = 0;
+    // the body is cut off once the limit above is exceeded,
+    // which surfaces as an error of the multipart parser
+    let check_limit = |e: Error| match e {
+        Error::MalformedMultipart(_) if received.load(Ordering::Relaxed) > max_upload_size =>
+            Error::PayloadTooLarge(Limit::UploadSize(max_upload_size)),
+        e => e,
+    };
+    let multipart_error = |e: mpart_async::server::MultipartError| {
+        check_limit(Error::MalformedMultipart(e.to_string()))
+    };
+
+    // temporary files are removed when dropped, including on early returns
     let mut new_files: Vec<file::FileInfo> = Vec::new();
 
-    while let Ok(Some(mut form_field)) = parts.try_next().await {
-        match form_field.filename() {
-            Ok(filename) => {
-                let uuid = Arc::from(util::new_random_uuid());
-                let original_filename = Arc::from(filename);
+    while let Some(mut form_field) = parts.try_next().await.map_err(multipart_error)? {
+        // a per-upload ttl may be sent as a form field next to the files
+        let filename = field_filename(form_field.headers());
 
-                new_files.push(file::FileInfo {
-                    uuid,
-                    original_filename,
-                });
-            },
-            Err(_) => {
-                println!("Warn: client did not send filename, ignoring part");
+        if filename.is_none() && form_field.name().ok() == Some("ttl") {
+            let mut value = vec![];
+            while let Some(bytes) = form_field.try_next().await.map_err(multipart_error)? {
+                value.extend_from_slice(&bytes);
+            }
+            ttl = config.clamp_ttl(util::parse_ttl(&String::from_utf8_lossy(&value))
+                .map_err(Error::InvalidTtl)?);
+            continue
+        }
+
+        let field_name: Arc<str> = Arc::from(form_field.name().unwrap_or(""));
+
+        let original_filename: Arc<str> = match filename {
+            Some(filename) => Arc::from(util::sanitize_filename(&filename)),
+            None => {
+                warn!("client did not send filename, ignoring part");
+                while form_field.try_next().await.map_err(multipart_error)?.is_some() {}
                 continue
             },
+        };
+
+        if new_files.len() >= config.max_files {
+            return Err(Error::PayloadTooLarge(Limit::Files(config.max_files)));
         }
 
-        let name = util::prepend_tmp_dir(&new_files[file_index].uuid);
-        if let Ok(mut file) = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(name.as_str()) {
-            while let Ok(Some(bytes)) = form_field.try_next().await {
-                let _ = file.write(bytes.to_vec().as_ref());
+        let tmp_file = file::TmpFile::new_random(&config.storage);
+
+        let (sha256, size, head) = file::write_and_hash(
+                &tmp_file, &mut form_field, config.max_file_size).await
+            .map_err(check_limit)?;
+
+        let mime_type = sniff::detect(&head, &original_filename);
+
+        new_files.push(file::FileInfo {
+            field_name,
+            original_filename,
+            tmp_file,
+            sha256: Arc::from(sha256),
+            size,
+            mime_type: Arc::from(mime_type),
+        });
+    }
+
+    if new_files.is_empty() {
+        return Err(Error::NoFiles);
+    }
+
+    let expiry = ttl.map(|t| util::unix_now().saturating_add(t));
+
+    let tasks = create_upload_tasks(new_files, expiry, uploader, &config.storage, db).await;
+
+    Ok(futures::future::join_all(tasks).await)
+}
+
+/// Returns the filename of a multipart field, preferring `filename*` as per
+/// RFC 5987. Unlike the multipart parser, this accepts non-ASCII names.
+fn field_filename(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get("content-disposition")?;
+    let header = String::from_utf8_lossy(header.as_bytes());
+
+    let params = disposition_params(&header);
+
+    let extended = params.iter()
+        .filter(|(name, _)| name == "filename*")
+        .find_map(|(_, value)| {
+            let mut parts = value.splitn(3, '\'');
+            let charset = parts.next()?;
+            let _language = parts.next()?;
+            match charset.eq_ignore_ascii_case("utf-8") {
+                true => percent_decode_str(parts.next()?).decode_utf8().ok().map(|s| s.into_owned()),
+                false => None,
             }
-            file_index += 1;
-        } else {
-          println!("error creating temporary file {}", name);
-          return Err(warp::reject::not_found());
+        });
+
+    extended.or_else(|| params.into_iter()
+        .find(|(name, _)| name == "filename")
+        .map(|(_, value)| value))
+}
+
+/// Splits the parameters of a `Content-Disposition` value into lowercase
+/// names and unquoted values.
+fn disposition_params(header: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+
+    // the disposition type itself is skipped
+    let mut rest = match header.split_once(';') {
+        Some((_, rest)) => rest,
+        None => return params,
+    };
+
+    while let Some((name, value)) = rest.split_once('=') {
+        let value = value.trim_start();
+
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => unquote(quoted),
+            None => {
+                let end = value.find(';').unwrap_or(value.len());
+                (value[..end].trim_end().to_owned(), &value[end..])
+            },
         };
 
+        params.push((name.trim().to_ascii_lowercase(), value));
+
+        rest = match remainder.split_once(';') {
+            Some((_, rest)) => rest,
+            None => break,
+        };
     }
 
-    let tasks = create_upload_tasks(new_files, db).await;
+    params
+}
 
-    let maybe_urls = futures::future::join_all(tasks).await;
+/// Reads a quoted string up to its closing quote, returning its content and
+/// whatever follows. Browsers percent-encode quotes and send backslashes as
+/// they are, so only `\"` and `\\` are treated as escapes.
+fn unquote(quoted: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices().peekable();
 
-    let mut response = vec![];
-    for url in maybe_urls {
-        match url {
-            Some(short_id) => response.push(config.prepend_url(SuffixType::ShortID, &short_id)),
-            None => response.push(String::from("upload failed")),
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (value, &quoted[i + 1..]),
+            '\\' => match chars.peek() {
+                Some((_, next @ ('"' | '\\'))) => {
+                    value.push(*next);
+                    chars.next();
+                },
+                _ => value.push(c),
+            },
+            c => value.push(c),
         }
     }
 
-    Ok(format!("{}\n", response.join("\n")))
+    (value, "")
 }
@@ -0,0 +1,142 @@
+use crate::config::Config;
+use crate::error::Error;
+
+use tokio::net::{TcpListener, UnixListener};
+use tracing::warn;
+
+use std::fmt;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+/// An address to accept connections on.
+#[derive(Clone, PartialEq)]
+pub enum ListenAddr {
+    Unix(String),
+    /// `host:port`, the host may be a name or an IP address
+    Tcp(String),
+}
+
+impl ListenAddr {
+    /// Parses `tcp://<host>:<port>` and `unix:<path>`, a plain path is a Unix socket.
+    pub fn parse(s: &str) -> Result<ListenAddr, String> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            let addr = addr.trim_end_matches('/');
+            let port = addr.rsplit_once(':')
+                .filter(|(host, _)| !host.is_empty())
+                .map(|(_, port)| port)
+                .ok_or_else(|| format!("invalid address '{}', expected tcp://<host>:<port>", s))?;
+            return match port.parse::<u16>() {
+                Ok(port) if port > 0 => Ok(ListenAddr::Tcp(addr.to_owned())),
+                _ => Err(format!("invalid port '{}' in '{}', expected 1-65535", port, s)),
+            };
+        }
+
+        let path = s.strip_prefix("unix://").or_else(|| s.strip_prefix("unix:")).unwrap_or(s);
+        match path.is_empty() || path.contains("://") {
+            true => Err(format!("invalid address '{}', expected tcp://<host>:<port> \
+                or unix:<path>", s)),
+            false => Ok(ListenAddr::Unix(path.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path),
+            ListenAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Binds all configured addresses, failing if any of them cannot be bound.
+pub async fn bind_all(config: &Config) -> Result<Vec<Listener>, Error> {
+    let mut listeners = vec![];
+
+    for addr in config.listen.iter() {
+        listeners.push(bind(addr, config).await?);
+    }
+
+    Ok(listeners)
+}
+
+pub async fn bind(addr: &ListenAddr, config: &Config) -> Result<Listener, Error> {
+    Ok(match addr {
+        ListenAddr::Unix(path) => Listener::Unix(bind_unix(path, config)?),
+        ListenAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await
+            .map_err(|e| bind_error(addr, e))?),
+    })
+}
+
+fn bind_unix(path: &str, config: &Config) -> Result<UnixListener, Error> {
+    remove_stale_socket(path)?;
+
+    let listener = UnixListener::bind(path).map_err(|e| bind_error(path, e))?;
+
+    std::fs::set_permissions(path, Permissions::from_mode(config.socket_mode))?;
+
+    if config.socket_owner.is_some() || config.socket_group.is_some() {
+        std::os::unix::fs::chown(path, config.socket_owner, config.socket_group)?;
+    }
+
+    Ok(listener)
+}
+
+/// A socket file nobody accepts connections on is left over from a crash
+/// and removed, one that is still in use is not.
+fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(bind_error(path, std::io::Error::new(
+            ErrorKind::AlreadyExists, "file exists and is not a socket")));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("removing stale socket {}", path);
+            std::fs::remove_file(path)?;
+            Ok(())
+        },
+        Ok(_) => Err(bind_error(path, std::io::Error::new(
+            ErrorKind::AddrInUse, "socket is in use by another process"))),
+        Err(e) => Err(bind_error(path, e)),
+    }
+}
+
+fn bind_error(addr: &str, e: std::io::Error) -> Error {
+    std::io::Error::new(e.kind(), format!("failed to listen on {}: {}", addr, e)).into()
+}
+
+/// Parses an octal file mode such as `660` or `0o660`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid mode '{}', expected octal permissions such as 660", s)),
+    }
+}
+
+/// Resolves a user name or numeric uid.
+pub fn parse_user(s: &str) -> Result<u32, String> {
+    s.parse().or_else(|_| users::get_user_by_name(s)
+        .map(|user| user.uid())
+        .ok_or_else(|| format!("unknown user '{}'", s)))
+}
+
+/// Resolves a group name or numeric gid.
+pub fn parse_group(s: &str) -> Result<u32, String> {
+    s.parse().or_else(|_| users::get_group_by_name(s)
+        .map(|group| group.gid())
+        .ok_or_else(|| format!("unknown group '{}'", s)))
+}
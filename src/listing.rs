@@ -0,0 +1,261 @@
+use crate::db::{self, ScanOrder, Upload};
+use crate::error::Error;
+use crate::sniff;
+use crate::util;
+
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+
+/// The query parameters accepted by `/state`.
+#[derive(Deserialize)]
+pub struct StateQuery {
+    pub format: Option<String>,
+    limit:      Option<String>,
+    cursor:     Option<String>,
+    sort:       Option<String>,
+    order:      Option<String>,
+    name:       Option<String>,
+    mime:       Option<String>,
+    sha256:     Option<String>,
+}
+
+impl StateQuery {
+    /// Returns the query string that continues this listing at `cursor`.
+    pub fn with_cursor(&self, cursor: &str) -> String {
+        let params = [
+            ("format", self.format.as_deref()),
+            ("limit",  self.limit.as_deref()),
+            ("cursor", Some(cursor)),
+            ("sort",   self.sort.as_deref()),
+            ("order",  self.order.as_deref()),
+            ("name",   self.name.as_deref()),
+            ("mime",   self.mime.as_deref()),
+            ("sha256", self.sha256.as_deref()),
+        ];
+
+        let params: Vec<String> = params.iter()
+            .filter_map(|(key, value)| value.map(|v| format!("{}={}", key, util::encode_path_segment(v))))
+            .collect();
+
+        format!("?{}", params.join("&"))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sort {
+    Id,
+    Time,
+    Size,
+    Name,
+}
+
+/// The position of an upload in a listing, ties are broken by its short id.
+enum Key {
+    Number(u64),
+    Text(String),
+}
+
+pub struct Listing {
+    sort:       Sort,
+    descending: bool,
+    limit:      usize,
+    /// the position of the last upload on the previous page
+    cursor:     Option<(Key, String)>,
+    name:       Option<String>,
+    mime:       Option<String>,
+    sha256:     Option<String>,
+    /// the name of the API key whose uploads are listed, all if `None`
+    owner:      Option<String>,
+}
+
+pub struct Page {
+    pub uploads:     Vec<Upload>,
+    pub next_cursor: Option<String>,
+}
+
+impl Listing {
+    pub fn from_query(query: &StateQuery, owner: Option<&str>) -> Result<Listing, Error> {
+        let sort = match query.sort.as_deref() {
+            None | Some("id") => Sort::Id,
+            Some("time") => Sort::Time,
+            Some("size") => Sort::Size,
+            Some("name") => Sort::Name,
+            Some(s) => return Err(Error::InvalidQuery(
+                format!("unknown sort key '{}', expected id, time, size or name", s))),
+        };
+
+        let descending = match query.order.as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(s) => return Err(Error::InvalidQuery(
+                format!("unknown order '{}', expected asc or desc", s))),
+        };
+
+        let limit = match query.limit.as_deref().map(str::parse::<usize>) {
+            None => DEFAULT_LIMIT,
+            Some(Ok(n)) if (1..=MAX_LIMIT).contains(&n) => n,
+            Some(_) => return Err(Error::InvalidQuery(
+                format!("limit must be between 1 and {}", MAX_LIMIT))),
+        };
+
+        let cursor = match &query.cursor {
+            Some(cursor) => Some(parse_cursor(sort, cursor)
+                .ok_or_else(|| Error::InvalidQuery(format!("invalid cursor '{}'", cursor)))?),
+            None => None,
+        };
+
+        Ok(Listing {
+            sort,
+            descending,
+            limit,
+            cursor,
+            name: query.name.as_ref().map(|s| s.to_lowercase()),
+            mime: query.mime.as_ref().map(|s| s.to_lowercase()),
+            sha256: query.sha256.as_ref().map(|s| s.to_lowercase()),
+            owner: owner.map(str::to_owned),
+        })
+    }
+
+    /// Every order is kept by an index in the database, pages are read directly.
+    pub async fn fetch(&self, db: sled::Db) -> Result<Page, Error> {
+        let order = match self.sort {
+            Sort::Id => ScanOrder::Id,
+            Sort::Time => ScanOrder::Created,
+            Sort::Size => ScanOrder::Size,
+            Sort::Name => ScanOrder::Name,
+        };
+
+        let after = match (&self.cursor, self.sort) {
+            (None, _) => None,
+            (Some((_, id)), Sort::Id) => Some(id.as_bytes().to_vec()),
+            (Some((Key::Number(created), id)), Sort::Time) => Some(db::created_key(*created, id.as_bytes())),
+            (Some((Key::Number(size), id)), Sort::Size) => Some(db::size_key(*size, id.as_bytes())),
+            (Some((Key::Text(name), id)), Sort::Name) => Some(db::name_key(name, id.as_bytes())),
+            // cursors are parsed according to the sort key
+            (Some(_), _) => None,
+        };
+
+        self.paginate(db::scan_uploads(&db, order, self.descending, after.as_deref())?)
+    }
+
+    fn paginate(&self, uploads: impl Iterator<Item = Result<Upload, Error>>) -> Result<Page, Error> {
+        let mut page = vec![];
+
+        for upload in uploads {
+            let upload = upload?;
+
+            if !self.matches(&upload) {
+                continue
+            }
+
+            // only hand out a cursor if there actually is another page
+            if page.len() == self.limit {
+                let next_cursor = page.last().map(|last| self.cursor_of(last));
+                return Ok(Page { uploads: page, next_cursor });
+            }
+
+            page.push(upload);
+        }
+
+        Ok(Page { uploads: page, next_cursor: None })
+    }
+
+    fn matches(&self, upload: &Upload) -> bool {
+        if self.owner.is_some() && upload.metadata.uploader.api_key != self.owner {
+            return false;
+        }
+
+        let name = upload.metadata.filename.as_deref().unwrap_or("");
+
+        if let Some(needle) = &self.name {
+            if !name.to_lowercase().contains(needle.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(mime) = &self.mime {
+            // "image/*" matches all image types
+            if !sniff::matches_any(&upload.metadata.mime_type, std::slice::from_ref(mime)) {
+                return false;
+            }
+        }
+
+        if let Some(prefix) = &self.sha256 {
+            if !upload.checksum.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn key_of(&self, upload: &Upload) -> (Key, String) {
+        let key = match self.sort {
+            Sort::Id => Key::Number(0),
+            Sort::Time => Key::Number(upload.metadata.uploaded_at.unwrap_or(0)),
+            Sort::Size => Key::Number(upload.metadata.size.unwrap_or(0)),
+            Sort::Name => Key::Text(upload.metadata.filename.clone().unwrap_or_default()),
+        };
+        (key, upload.id.clone())
+    }
+
+    fn cursor_of(&self, upload: &Upload) -> String {
+        match self.key_of(upload) {
+            (_, id) if self.sort == Sort::Id => id,
+            (Key::Number(n), id) => format!("{}.{}", n, id),
+            (Key::Text(s), id) => format!("{}.{}", util::encode_path_segment(&s), id),
+        }
+    }
+}
+
+/// Cursors are the sort key and the short id of the last upload on a page,
+/// joined by a dot. Short ids never contain dots, names are percent-encoded.
+fn parse_cursor(sort: Sort, cursor: &str) -> Option<(Key, String)> {
+    if sort == Sort::Id {
+        return Some((Key::Number(0), cursor.to_owned()));
+    }
+
+    let (key, id) = cursor.rsplit_once('.')?;
+
+    let key = match sort {
+        Sort::Name => Key::Text(percent_decode_str(key).decode_utf8().ok()?.into_owned()),
+        _ => Key::Number(key.parse().ok()?),
+    };
+
+    Some((key, id.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(sort: Sort, cursor: &str) -> Option<String> {
+        parse_cursor(sort, cursor).map(|(key, id)| match key {
+            Key::Number(n) => format!("{} {}", n, id),
+            Key::Text(s) => format!("{:?} {}", s, id),
+        })
+    }
+
+    #[test]
+    fn parses_cursors() {
+        assert_eq!(parsed(Sort::Id, "abc"), Some("0 abc".to_owned()));
+        assert_eq!(parsed(Sort::Time, "1700000000.abc"), Some("1700000000 abc".to_owned()));
+        assert_eq!(parsed(Sort::Size, "0.x1"), Some("0 x1".to_owned()));
+
+        let name = util::encode_path_segment("my cat.v2.gif");
+        assert_eq!(parsed(Sort::Name, &format!("{}.abc", name)),
+            Some("\"my cat.v2.gif\" abc".to_owned()));
+        assert_eq!(parsed(Sort::Name, ".abc"), Some("\"\" abc".to_owned()));
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        assert_eq!(parsed(Sort::Time, "abc"), None);
+        assert_eq!(parsed(Sort::Size, "-1.abc"), None);
+        assert_eq!(parsed(Sort::Size, "1k.abc"), None);
+        assert_eq!(parsed(Sort::Name, "%FF.abc"), None);
+    }
+}
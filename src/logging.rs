@@ -0,0 +1,136 @@
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    match s {
+        "off"   => Ok(LevelFilter::OFF),
+        "error" => Ok(LevelFilter::ERROR),
+        "warn"  => Ok(LevelFilter::WARN),
+        "info"  => Ok(LevelFilter::INFO),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "trace" => Ok(LevelFilter::TRACE),
+        s => Err(format!("unknown log level '{}', expected off, error, warn, info, debug or trace", s)),
+    }
+}
+
+/// Logs to stderr, stdout is left to the output of admin commands. Libraries
+/// only log warnings and errors, their debug output is mostly noise.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let targets = Targets::new()
+        .with_target("urlnao", level)
+        .with_target("access", level)
+        .with_default(level.min(LevelFilter::WARN));
+
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Text => builder.finish().with(targets).init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_span_list(false)
+            .finish().with(targets).init(),
+    }
+}
+
+/// What the access log records about a request.
+pub struct Access<'a> {
+    pub id:         &'a str,
+    /// the address the proxy added to `X-Forwarded-For`, or the peer of a TCP connection
+    pub remote:     Option<&'a str>,
+    /// the user the proxy authenticated, never set on TCP connections
+    pub user:       Option<&'a str>,
+    pub time:       SystemTime,
+    pub method:     &'a str,
+    pub uri:        &'a str,
+    pub version:    &'a str,
+    pub status:     u16,
+    pub bytes:      u64,
+    pub referer:    Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// until the response headers were ready, bodies are streamed afterwards
+    pub duration:   Duration,
+}
+
+/// Logs a request in the combined log format followed by its ID and duration,
+/// or as separate fields in JSON.
+pub fn access(entry: &Access, format: LogFormat) {
+    let duration_ms = entry.duration.as_micros() as f64 / 1000.0;
+
+    match format {
+        LogFormat::Text => info!(target: "access", "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {:.3}ms",
+            entry.remote.unwrap_or("-"),
+            entry.user.unwrap_or("-"),
+            combined_time(entry.time),
+            entry.method,
+            entry.uri,
+            entry.version,
+            entry.status,
+            entry.bytes,
+            entry.referer.unwrap_or("-"),
+            entry.user_agent.unwrap_or("-"),
+            entry.id,
+            duration_ms),
+        LogFormat::Json => info!(target: "access",
+            request_id = entry.id,
+            remote = entry.remote.unwrap_or(""),
+            user = entry.user.unwrap_or(""),
+            method = entry.method,
+            uri = entry.uri,
+            version = entry.version,
+            status = entry.status,
+            bytes = entry.bytes,
+            referer = entry.referer.unwrap_or(""),
+            user_agent = entry.user_agent.unwrap_or(""),
+            duration_ms,
+            "request"),
+    }
+}
+
+/// Formats a time like `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn combined_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day, MONTHS[month as usize - 1], year, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Converts days since the epoch to a date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
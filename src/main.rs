@@ -1,56 +1,194 @@
+mod admin;
+mod auth;
 mod config;
 mod db;
+mod error;
 mod file;
+mod gc;
+mod html;
 mod http;
+mod listen;
+mod listing;
+mod logging;
+mod metrics;
+mod migrate;
+mod range;
+mod reaper;
+mod sniff;
 mod util;
 
-use config::Config;
+use config::{Config, LiveConfig};
 
-use futures::future;
-use signal_hook::consts::TERM_SIGNALS;
+use arc_swap::ArcSwap;
+use futures::future::{self, Either};
+use signal_hook::consts::{SIGHUP, TERM_SIGNALS};
 use signal_hook::iterator::Signals;
-use tokio_stream::wrappers::UnixListenerStream;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
-    let config = Config::init();
+    let config = match Config::init().and_then(|config| config.validate().map(|_| config)) {
+        Ok(config) => config,
+        Err(e) => {
+            // logging is configured by the configuration
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    config.print();
+    logging::init(config.log_level, config.log_format);
+
+    if config.check_config {
+        print!("{}", config.describe());
+        return;
+    }
+
+    if config.dry_run_migrations {
+        if let Err(e) = db::dry_run_migrations(config.db_path.clone(), &config.storage).await {
+            error!("failed to open database {}: {}", config.db_path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = config.storage.prepare() {
+        error!("failed to prepare data directories: {}", e);
+        std::process::exit(1);
+    }
 
-    let db = match db::open(config.db_path.clone()).await {
+    let db = match db::open(config.db_path.clone(), &config.storage).await {
         Ok(db) => db,
-        Err(_) => {
-            eprintln!("Error: failed to open database {}", config.db_path);
+        Err(e) => {
+            error!("failed to open database {}: {}", config.db_path, e);
             std::process::exit(1);
         }
     };
 
-    let listener = match file::setup_dirs_get_listener(&config.socket_path).await {
-        Ok(listener) => listener,
+    if let Some(command) = &config.command {
+        if let Err(e) = admin::run(command, &config.storage, db).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    config.print();
+
+    let listeners = match listen::bind_all(&config).await {
+        Ok(listeners) => listeners,
         Err(e) => {
-            eprintln!("Error: {}", e);
+            error!("{}", e);
             std::process::exit(1);
         }
     };
 
-    let incoming = UnixListenerStream::new(listener);
+    let metrics_listener = match &config.metrics_listen {
+        Some(addr) => match listen::bind(addr, &config).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // nothing is in progress yet, any temporary file is left over
+    let options = gc::Options { report_only: false, rehash: false, grace_period: Duration::from_secs(0) };
+    match gc::run(db.clone(), &config.storage, &options).await {
+        Ok(report) => report.log(false),
+        Err(e) => error!("garbage collection failed: {}", e),
+    }
+
+    let live_config: LiveConfig = Arc::new(ArcSwap::from_pointee(config.clone()));
+
+    let (start_draining, draining) = watch::channel(false);
+
+    let server = http::create_server(db.clone(), &live_config, listeners, draining);
+
+    if let Some(listener) = metrics_listener {
+        http::create_metrics_server(listener);
+        metrics::spawn(db.clone());
+    }
+
+    // runs until the process exits, off the runtime so that it does not delay shutdown
+    let reload_config = live_config.clone();
+    std::thread::spawn(move || reload_on_hangup(reload_config));
+
+    if let Some(interval) = config.gc_interval {
+        gc::spawn(db.clone(), config.storage.clone(), Duration::from_secs(interval));
+    }
+
+    reaper::spawn(db.clone(), config.storage.clone());
 
-    let server = http::create_server(db, &config, incoming);
+    // waiting for signals blocks, keep it off the runtime's worker threads
+    let sigwait = tokio::task::spawn_blocking(term_signal);
 
-    let sigwait = tokio::spawn(async move {
-        term_signal().await
-    });
+    if let Either::Right((_, server)) = future::select(server, sigwait).await {
+        let _ = start_draining.send(true);
 
-    future::select(server, sigwait).await;
+        let in_flight = file::tmp_files_in_flight();
+        match live_config.load().drain_timeout {
+            Some(timeout) => {
+                if in_flight > 0 {
+                    info!("waiting up to {} seconds for {} upload(s) in progress",
+                        timeout, in_flight);
+                }
+                if tokio::time::timeout(Duration::from_secs(timeout), server).await.is_err() {
+                    warn!("drain timeout elapsed, aborting {} upload(s) in progress",
+                        file::tmp_files_in_flight());
+                }
+            },
+            None => {
+                if in_flight > 0 {
+                    info!("waiting for {} upload(s) in progress", in_flight);
+                }
+                let _ = server.await;
+            },
+        }
+    }
+
+    // uploads that did not finish in time are abandoned with their temporary files
+    match gc::remove_tmp_files(&config.storage).await {
+        Ok(removed) if !removed.is_empty() => {
+            info!("removed {} temporary file(s) of aborted uploads", removed.len());
+        },
+        Ok(_) => (),
+        Err(e) => error!("failed to remove temporary files: {}", e),
+    }
+
+    if let Err(e) = db.flush_async().await {
+        error!("failed to flush database: {}", e);
+    }
 
     util::cleanup(&config);
 }
 
-async fn term_signal() {
+fn term_signal() {
     let mut signals = Signals::new(TERM_SIGNALS).unwrap();
 
-    for _ in &mut signals {
-        eprintln!("Received signal, terminating.");
-        break;
+    if signals.forever().next().is_some() {
+        info!("received signal, terminating");
+    }
+}
+
+fn reload_on_hangup(live_config: LiveConfig) {
+    let mut signals = Signals::new([SIGHUP]).unwrap();
+
+    for _ in signals.forever() {
+        match live_config.load().reload() {
+            Ok((config, changes)) => {
+                info!("reloaded configuration, {} setting(s) changed", changes.len());
+                for change in changes {
+                    info!("  {}", change);
+                }
+                live_config.store(Arc::new(config));
+            },
+            Err(e) => error!("failed to reload configuration, keeping the current one: {}", e),
+        }
     }
 }
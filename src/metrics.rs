@@ -0,0 +1,230 @@
+use crate::error::Error;
+use crate::file;
+
+use tracing::error;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Files stored by uploads, including duplicates.
+pub static UPLOADS: Counter = Counter::new();
+/// Failed uploads of single files and failed upload requests, by error kind.
+pub static UPLOAD_FAILURES: LabeledCounter = LabeledCounter::new();
+pub static UPLOAD_SIZE: Histogram = Histogram::new(&SIZE_BUCKETS);
+/// Time taken by upload requests, successful or not.
+pub static UPLOAD_DURATION: Histogram = Histogram::new(&DURATION_BUCKETS);
+/// Download requests, by route.
+pub static DOWNLOADS: LabeledCounter = LabeledCounter::new();
+/// Uploads of content that was already stored, which share its blob.
+pub static DEDUP_HITS: Counter = Counter::new();
+/// Generated short ids that were already taken.
+pub static SHORT_ID_RETRIES: Counter = Counter::new();
+/// Files in `uploads/` and their bytes, recounted by each garbage collection
+/// pass and adjusted as blobs are stored and removed in between.
+pub static STORAGE_BLOBS: Gauge = Gauge::new();
+pub static STORAGE_BYTES: Gauge = Gauge::new();
+/// Entries per database tree, sled counts them by reading every entry.
+static TREE_ENTRIES: Mutex<Vec<(String, usize)>> = Mutex::new(Vec::new());
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+const SIZE_BUCKETS: [f64; 8] = [
+    1024.0, 16384.0, 262144.0, 1048576.0, 16777216.0, 67108864.0, 268435456.0, 1073741824.0];
+
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0, 120.0];
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Gauge {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: u64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+            |value| Some(value.saturating_sub(n)));
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A counter per value of a single label.
+pub struct LabeledCounter(Mutex<BTreeMap<&'static str, u64>>);
+
+impl LabeledCounter {
+    const fn new() -> LabeledCounter {
+        LabeledCounter(Mutex::new(BTreeMap::new()))
+    }
+
+    pub fn inc(&self, label: &'static str) {
+        *self.0.lock().unwrap().entry(label).or_insert(0) += 1;
+    }
+}
+
+pub struct Histogram {
+    /// upper bounds of the buckets, `+Inf` is implied
+    bounds: &'static [f64],
+    state:  Mutex<HistogramState>,
+}
+
+struct HistogramState {
+    /// observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum:    f64,
+    count:  u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState { counts: Vec::new(), sum: 0.0, count: 0 }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        if state.counts.is_empty() {
+            state.counts = vec![0; self.bounds.len()];
+        }
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            state.counts[bucket] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+}
+
+/// Periodically counts the entries of each database tree, so that scrapes
+/// only read the last count.
+pub fn spawn(db: sled::Db) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            match count_tree_entries(&db) {
+                Ok(entries) => *TREE_ENTRIES.lock().unwrap() = entries,
+                Err(e) => error!("failed to count database entries: {}", e),
+            }
+        }
+    })
+}
+
+fn count_tree_entries(db: &sled::Db) -> Result<Vec<(String, usize)>, Error> {
+    let mut names = db.tree_names();
+    names.sort();
+
+    let mut entries = vec![];
+    for name in names {
+        let tree = db.open_tree(&name)?;
+        entries.push((String::from_utf8_lossy(&name).into_owned(), tree.len()));
+    }
+
+    Ok(entries)
+}
+
+/// Renders all metrics in the Prometheus text format from the figures
+/// kept in memory.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    counter(&mut out, "urlnao_uploads_total", "Files stored by uploads.", UPLOADS.get());
+    labeled_counter(&mut out, "urlnao_upload_failures_total",
+        "Failed uploads of files and failed upload requests, by reason.",
+        "reason", &UPLOAD_FAILURES);
+    histogram(&mut out, "urlnao_upload_size_bytes", "Size of stored files.", &UPLOAD_SIZE);
+    histogram(&mut out, "urlnao_upload_duration_seconds", "Duration of upload requests.",
+        &UPLOAD_DURATION);
+    labeled_counter(&mut out, "urlnao_download_requests_total", "Download requests, by route.",
+        "route", &DOWNLOADS);
+    counter(&mut out, "urlnao_dedup_hits_total",
+        "Uploads of content that was already stored.", DEDUP_HITS.get());
+    counter(&mut out, "urlnao_short_id_retries_total",
+        "Generated short ids that were already taken.", SHORT_ID_RETRIES.get());
+
+    gauge(&mut out, "urlnao_storage_blobs", "Blobs in the uploads directory.", STORAGE_BLOBS.get());
+    gauge(&mut out, "urlnao_storage_bytes", "Bytes taken up by blobs in the uploads directory.",
+        STORAGE_BYTES.get());
+    gauge(&mut out, "urlnao_tmp_files_in_flight", "Temporary files of uploads in progress.",
+        file::tmp_files_in_flight() as u64);
+
+    header(&mut out, "urlnao_db_tree_entries", "gauge", "Entries per database tree.");
+    for (name, entries) in TREE_ENTRIES.lock().unwrap().iter() {
+        let _ = writeln!(out, "urlnao_db_tree_entries{{tree=\"{}\"}} {}", escape(name), entries);
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn labeled_counter(out: &mut String, name: &str, help: &str, label: &str, counter: &LabeledCounter) {
+    header(out, name, "counter", help);
+    for (value, count) in counter.0.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, "histogram", help);
+    let state = histogram.state.lock().unwrap();
+    let mut cumulative = 0;
+    for (i, bound) in histogram.bounds.iter().enumerate() {
+        cumulative += state.counts.get(i).copied().unwrap_or(0);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+    let _ = writeln!(out, "{}_sum {}", name, state.sum);
+    let _ = writeln!(out, "{}_count {}", name, state.count);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
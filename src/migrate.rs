@@ -0,0 +1,372 @@
+use crate::db::{self, Metadata, Uploader};
+use crate::error::Error;
+use crate::file::{self, Storage};
+use crate::sniff;
+
+use sled::IVec;
+use sled::transaction::ConflictableTransactionResult;
+use sled::Transactional;
+use tracing::{info, warn};
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::str::from_utf8;
+
+/// The schema version this binary reads and writes.
+pub const SCHEMA_VERSION: u64 = 1;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+struct Migration {
+    /// the schema version after the migration
+    version:     u64,
+    description: &'static str,
+    plan:        fn(&sled::Db, &Storage) -> Result<Plan, Error>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "store metadata per upload, count references to blobs, \
+            index uploads and shard blobs",
+        plan: plan_per_upload_records,
+    },
+];
+
+/// The changes of a single migration, applied in one transaction.
+#[derive(Default)]
+struct Plan {
+    /// `None` removes the key
+    changes: BTreeMap<&'static str, Vec<(IVec, Option<IVec>)>>,
+    /// trees that are no longer used once the changes are applied
+    dropped: Vec<&'static str>,
+    /// blobs to move on disk before the changes are applied, from and to
+    moves:   Vec<(String, String)>,
+}
+
+impl Plan {
+    fn insert(&mut self, tree: &'static str, key: impl Into<IVec>, value: impl Into<IVec>) {
+        self.changes.entry(tree).or_default().push((key.into(), Some(value.into())));
+    }
+
+    fn remove(&mut self, tree: &'static str, key: impl Into<IVec>) {
+        self.changes.entry(tree).or_default().push((key.into(), None));
+    }
+
+    /// Removes all keys of a tree and drops it afterwards.
+    fn drop_tree(&mut self, db: &sled::Db, tree: &'static str) -> Result<(), Error> {
+        for key in db.open_tree(tree)?.iter().keys() {
+            self.remove(tree, key?);
+        }
+        self.dropped.push(tree);
+        Ok(())
+    }
+
+    // dropping empty trees alone is not worth mentioning
+    fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.moves.is_empty()
+    }
+
+    fn describe(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.changes.iter()
+            .map(|(tree, changes)| {
+                let set = changes.iter().filter(|(_, value)| value.is_some()).count();
+                format!("{}: set {} key(s), remove {} key(s)", tree, set, changes.len() - set)
+            })
+            .collect();
+        lines.extend(self.dropped.iter().map(|tree| format!("{}: drop tree", tree)));
+        if !self.moves.is_empty() {
+            lines.push(format!("uploads: move {} blob(s) into the sharded layout",
+                self.moves.len()));
+        }
+        lines
+    }
+}
+
+/// Upgrades the database to [`SCHEMA_VERSION`], one migration at a time.
+pub fn run(db: &sled::Db, storage: &Storage) -> Result<(), Error> {
+    let version = read_version(db)?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let plan = (migration.plan)(db, storage)?;
+
+        if !plan.is_empty() {
+            info!("migrating database to schema version {}: {}",
+                     migration.version, migration.description);
+            for line in plan.describe() {
+                info!("  {}", line);
+            }
+        }
+
+        move_blobs(&plan)?;
+        apply(db, migration.version, &plan)?;
+    }
+
+    Ok(())
+}
+
+/// Prints the changes that [`run`] would make, without modifying the database.
+pub fn dry_run(db: &sled::Db, storage: &Storage) -> Result<(), Error> {
+    let version = read_version(db)?;
+
+    if version == SCHEMA_VERSION {
+        println!("database is at schema version {}, nothing to migrate", version);
+        return Ok(());
+    }
+
+    // migrations build on each other, so they are applied to a scratch copy,
+    // blobs are left where they are
+    let scratch = sled::Config::new().temporary(true).open()?;
+    scratch.import(db.export());
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let plan = (migration.plan)(&scratch, storage)?;
+
+        println!("would migrate to schema version {}: {}", migration.version, migration.description);
+        match plan.is_empty() {
+            true => println!("  no changes"),
+            false => plan.describe().iter().for_each(|line| println!("  {}", line)),
+        }
+
+        apply(&scratch, migration.version, &plan)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the schema version on disk, refusing versions that are newer
+/// than this binary understands.
+fn read_version(db: &sled::Db) -> Result<u64, Error> {
+    let meta = db.open_tree(b"meta")?;
+
+    let version = match meta.get(SCHEMA_VERSION_KEY)? {
+        Some(ivec) if ivec.len() == 8 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&ivec);
+            u64::from_be_bytes(bytes)
+        },
+        Some(_) => return Err(Error::Corrupted("invalid schema version".to_string())),
+        // databases written before schema versions were introduced
+        None => 0,
+    };
+
+    if version > SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchema(version));
+    }
+
+    Ok(version)
+}
+
+/// Moves blobs ahead of the database changes, an interrupted migration
+/// plans the remaining moves again on the next start.
+fn move_blobs(plan: &Plan) -> Result<(), Error> {
+    for (from, to) in &plan.moves {
+        if let Some(parent) = Path::new(to).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(from, to)?;
+    }
+    Ok(())
+}
+
+fn apply(db: &sled::Db, version: u64, plan: &Plan) -> Result<(), Error> {
+    let mut names: Vec<&str> = plan.changes.keys().copied().collect();
+    if !names.contains(&"meta") {
+        names.push("meta");
+    }
+
+    let trees = names.iter()
+        .map(|name| db.open_tree(name))
+        .collect::<Result<Vec<sled::Tree>, _>>()?;
+
+    trees.as_slice().transaction(|tx_trees| -> ConflictableTransactionResult<(), Error> {
+        for (tx_tree, name) in tx_trees.iter().zip(&names) {
+            for (key, value) in plan.changes.get(name).into_iter().flatten() {
+                match value {
+                    Some(value) => tx_tree.insert(key, value.clone())?,
+                    None => tx_tree.remove(key)?,
+                };
+            }
+
+            if *name == "meta" {
+                tx_tree.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    db.flush()?;
+
+    for tree in &plan.dropped {
+        db.drop_tree(tree)?;
+    }
+
+    Ok(())
+}
+
+/// The first release stored a single short id per checksum, found through
+/// `sha_to_id`, and original filenames per checksum in `sha_to_orig` and
+/// `orig_to_sha`. Every upload now has its own metadata record, is indexed
+/// by upload time, size and filename and counts as a reference to its blob,
+/// which is moved into the sharded layout, e.g. `uploads/ab/cd/abcd…`.
+/// Existing `/d/<name>` links resolve to short ids through `orig_to_id`.
+fn plan_per_upload_records(db: &sled::Db, storage: &Storage) -> Result<Plan, Error> {
+    let id_to_sha = db.open_tree(b"id_to_sha")?;
+
+    let sha_to_id = db.open_tree(b"sha_to_id")?;
+
+    let sha_to_orig = db.open_tree(b"sha_to_orig")?;
+
+    let orig_to_sha = db.open_tree(b"orig_to_sha")?;
+
+    let mut plan = Plan::default();
+    let mut refs: BTreeMap<IVec, u64> = BTreeMap::new();
+    let (mut uploads, mut bytes) = (0, 0);
+
+    for tuple in id_to_sha.iter() {
+        let (id_ivec, sha_ivec) = tuple?;
+
+        let sha = from_utf8(&sha_ivec)?;
+
+        let filename = match sha_to_orig.get(&sha_ivec)? {
+            Some(ivec) => Some(from_utf8(&ivec)?.to_owned()),
+            None => None,
+        };
+
+        // the upload time is unknown, such uploads are sorted as the oldest
+        let (size, mime_type) = match read_blob(&blob_path(storage, sha)) {
+            Ok((size, head)) => (Some(size),
+                sniff::detect(&head, filename.as_deref().unwrap_or(""))),
+            Err(e) => {
+                warn!("failed to read {}: {}", sha, e);
+                (None, sniff::from_filename(filename.as_deref().unwrap_or("")))
+            },
+        };
+
+        let metadata = Metadata {
+            version: db::METADATA_VERSION,
+            uploaded_at: None,
+            size,
+            mime_type,
+            filename,
+            field_name: None,
+            uploader: Uploader::default(),
+        };
+
+        plan.insert("created_to_id", db::created_key(0, &id_ivec), IVec::default());
+        plan.insert("size_to_id", db::size_key(size.unwrap_or(0), &id_ivec), IVec::default());
+        plan.insert("name_to_id",
+            db::name_key(metadata.filename.as_deref().unwrap_or(""), &id_ivec), IVec::default());
+        plan.insert("id_to_meta", id_ivec, metadata.to_vec()?);
+
+        *refs.entry(sha_ivec).or_insert(0) += 1;
+        uploads += 1;
+        bytes += size.unwrap_or(0);
+    }
+
+    for (sha_ivec, count) in refs {
+        plan.insert("sha_refs", sha_ivec, &count.to_be_bytes());
+    }
+
+    if uploads > 0 {
+        plan.insert("totals", db::totals_key(None), db::totals_to_vec(uploads, bytes));
+    }
+
+    for tuple in orig_to_sha.iter() {
+        let (orig_ivec, sha_ivec) = tuple?;
+
+        if let Some(id_ivec) = sha_to_id.get(&sha_ivec)? {
+            plan.insert("orig_to_id", orig_ivec, id_ivec);
+        }
+    }
+
+    plan.drop_tree(db, "sha_to_id")?;
+    plan.drop_tree(db, "sha_to_orig")?;
+    plan.drop_tree(db, "orig_to_sha")?;
+
+    let entries = match std::fs::read_dir(storage.uploads_dir()) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(plan),
+        entries => entries?,
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && file::is_checksum(&name) {
+            plan.moves.push((storage.flat_blob_path(&name), storage.blob_path(&name)));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Blobs are moved into the sharded layout before the database changes are
+/// applied, an interrupted migration finds some of them there already.
+fn blob_path(storage: &Storage, sha256: &str) -> String {
+    let path = storage.blob_path(sha256);
+    match Path::new(&path).exists() {
+        true => path,
+        false => storage.flat_blob_path(sha256),
+    }
+}
+
+/// Returns the size of a blob and its leading bytes.
+fn read_blob(path: &str) -> std::io::Result<(u64, Vec<u8>)> {
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
+    file.take(sniff::SNIFF_LEN as u64).read_to_end(&mut head)?;
+    Ok((size, head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    use sha2::Digest;
+
+    /// Links handed out by the first release must keep working, with the
+    /// database and blobs laid out as it left them.
+    #[tokio::test]
+    async fn migrates_baseline_database() {
+        let dir = std::env::temp_dir().join(format!("urlnao-{}", util::new_random_uuid()));
+        let storage = Storage::new(&dir.to_string_lossy(), None);
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let content = b"GIF89a";
+        let sha256 = format!("{:x}", sha2::Sha256::digest(content));
+        std::fs::create_dir_all(storage.uploads_dir()).unwrap();
+        std::fs::write(storage.flat_blob_path(&sha256), content).unwrap();
+
+        db.open_tree("id_to_sha").unwrap().insert("abc", sha256.as_bytes()).unwrap();
+        db.open_tree("sha_to_id").unwrap().insert(sha256.as_bytes(), "abc").unwrap();
+        db.open_tree("sha_to_orig").unwrap().insert(sha256.as_bytes(), "cat.gif").unwrap();
+        db.open_tree("orig_to_sha").unwrap().insert("cat.gif", sha256.as_bytes()).unwrap();
+
+        run(&db, &storage).unwrap();
+
+        assert_eq!(read_version(&db).unwrap(), SCHEMA_VERSION);
+
+        // `/f/abc` redirects to `/d/abc/cat.gif`
+        let (sha, metadata) = db::try_get_sha_and_metadata(db.clone(), b"abc").await.unwrap();
+        assert_eq!(sha, sha256);
+        assert_eq!(metadata.filename.as_deref(), Some("cat.gif"));
+        assert_eq!(metadata.size, Some(content.len() as u64));
+        assert_eq!(metadata.mime_type, "image/gif");
+
+        // `/d/cat.gif`
+        assert_eq!(db::try_get_id_for_orig(db.clone(), b"cat.gif").await.unwrap(), "abc");
+
+        assert!(Path::new(&storage.blob_path(&sha256)).is_file());
+        assert!(!Path::new(&storage.flat_blob_path(&sha256)).exists());
+        for tree in &["sha_to_id", "sha_to_orig", "orig_to_sha"] {
+            assert!(!db.tree_names().contains(&IVec::from(*tree)));
+        }
+        assert!(db::is_sha_referenced(&db, &sha256).unwrap());
+        assert_eq!(db::get_totals(db.clone(), None).await.unwrap(), (1, content.len() as u64));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
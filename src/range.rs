@@ -0,0 +1,142 @@
+// requests with more ranges than this are answered with the full file
+const MAX_RANGES: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    /// inclusive, as in the Content-Range header
+    pub end:   u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// Parses the value of a Range header for a file of `len` bytes.
+/// Syntactically invalid headers are ignored, as permitted by RFC 7233.
+pub fn parse(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    if specs.trim().is_empty() {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = vec![];
+
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = match spec.split_once('-') {
+            Some(t) => t,
+            None => return RangeRequest::Full,
+        };
+
+        let range = match (first.parse::<u64>(), last) {
+            // suffix range, e.g. "-500" for the last 500 bytes
+            (Err(_), last) if first.is_empty() => match last.parse::<u64>() {
+                Ok(0) => continue,
+                Ok(n) if len > 0 => ByteRange {
+                    start: len.saturating_sub(n),
+                    end:   len - 1,
+                },
+                Ok(_) => continue,
+                Err(_) => return RangeRequest::Full,
+            },
+            (Ok(start), "") => ByteRange {
+                start,
+                end: len.saturating_sub(1),
+            },
+            (Ok(start), last) => match last.parse::<u64>() {
+                Ok(end) if end >= start => ByteRange {
+                    start,
+                    end: end.min(len.saturating_sub(1)),
+                },
+                _ => return RangeRequest::Full,
+            },
+            (Err(_), _) => return RangeRequest::Full,
+        };
+
+        if range.start >= len {
+            continue
+        }
+
+        ranges.push(range);
+    }
+
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        n if n > MAX_RANGES => RangeRequest::Full,
+        _ => RangeRequest::Partial(ranges),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formats the outcome like `0-499,900-999`, `full` or `unsatisfiable`.
+    fn parsed(header: &str, len: u64) -> String {
+        match parse(header, len) {
+            RangeRequest::Full => "full".to_owned(),
+            RangeRequest::Unsatisfiable => "unsatisfiable".to_owned(),
+            RangeRequest::Partial(ranges) => ranges.iter()
+                .map(|r| format!("{}-{}", r.start, r.end))
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parsed("bytes=-500", 1000), "500-999");
+        assert_eq!(parsed("bytes=-2000", 1000), "0-999");
+        assert_eq!(parsed("bytes=-0", 1000), "unsatisfiable");
+        assert_eq!(parsed("bytes=-5", 0), "unsatisfiable");
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        assert_eq!(parsed("bytes=900-", 1000), "900-999");
+        assert_eq!(parsed("bytes=0-", 1000), "0-999");
+        assert_eq!(parsed("bytes=0-", 0), "unsatisfiable");
+    }
+
+    #[test]
+    fn ranges_past_the_end() {
+        assert_eq!(parsed("bytes=500-5000", 1000), "500-999");
+        assert_eq!(parsed("bytes=1000-", 1000), "unsatisfiable");
+        assert_eq!(parsed("bytes=1000-2000", 1000), "unsatisfiable");
+        // satisfiable ranges are served, the others skipped
+        assert_eq!(parsed("bytes=0-9,2000-2999", 1000), "0-9");
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        assert_eq!(parsed("bytes=0-499, 400-599", 1000), "0-499,400-599");
+        assert_eq!(parsed("bytes=0-0,-1,0-0", 1000), "0-0,999-999,0-0");
+    }
+
+    #[test]
+    fn invalid_headers() {
+        assert_eq!(parsed("bytes=5-1", 1000), "full");
+        assert_eq!(parsed("bytes=a-b", 1000), "full");
+        assert_eq!(parsed("bytes=5", 1000), "full");
+        assert_eq!(parsed("bytes=", 1000), "full");
+        assert_eq!(parsed("items=0-1", 1000), "full");
+        let many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i, i)).collect();
+        assert_eq!(parsed(&format!("bytes={}", many.join(",")), 1000), "full");
+    }
+}
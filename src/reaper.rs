@@ -0,0 +1,36 @@
+use crate::db;
+use crate::file::{self, Storage};
+use crate::util;
+
+use tracing::error;
+
+use std::time::Duration;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn spawn(db: sled::Db, storage: Storage) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            reap_expired(db.clone(), &storage).await;
+        }
+    })
+}
+
+pub async fn reap_expired(db: sled::Db, storage: &Storage) {
+    let unreferenced = match db::remove_expired(db.clone(), util::unix_now()).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("failed to remove expired uploads: {}", e);
+            return;
+        },
+    };
+
+    for sha256 in unreferenced {
+        if let Err(e) = file::try_remove_from_uploads(&sha256, storage, &db).await {
+            error!("{}", e);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+/// The number of leading bytes of an upload that are inspected.
+pub const SNIFF_LEN: usize = 8192;
+
+const FALLBACK: &str = "application/octet-stream";
+
+/// Detects the MIME type of an upload from its leading bytes, falling back
+/// to its file extension and then to whether it looks like text.
+pub fn detect(head: &[u8], filename: &str) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_owned();
+    }
+
+    if let Some(mime) = mime_guess::from_path(filename).first_raw() {
+        return mime.to_owned();
+    }
+
+    match looks_like_text(head) {
+        true => "text/plain".to_owned(),
+        false => FALLBACK.to_owned(),
+    }
+}
+
+/// Guesses the MIME type from the file extension alone, for uploads whose
+/// content is not at hand.
+pub fn from_filename(filename: &str) -> String {
+    mime_guess::from_path(filename).first_raw().unwrap_or(FALLBACK).to_owned()
+}
+
+/// Returns whether `mime` matches any of the patterns, e.g. `image/*`.
+pub fn matches_any(mime: &str, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => mime.starts_with(prefix),
+        None => mime == pattern,
+    })
+}
+
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // a multi-byte character may be cut off at the end of the head
+        Err(e) => e.error_len().is_none(),
+    }
+}
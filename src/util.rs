@@ -1,22 +1,216 @@
 use crate::config::Config;
+use crate::listen::ListenAddr;
 
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::{
+    prelude::*,
+    distributions::Alphanumeric
+};
+use sha2::Digest;
+use tracing::warn;
 use uuid::Uuid;
 use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn prepend_tmp_dir(s: &str) -> String {
-    format!("tmp/{}", s)
+// unreserved characters as per RFC 3986
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// attr-char as per RFC 5987
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+// most filesystems limit names to 255 bytes
+const MAX_FILENAME_LEN: usize = 255;
+
+pub fn encode_path_segment(s: &str) -> String {
+    utf8_percent_encode(s, PATH_SEGMENT).to_string()
 }
 
-pub fn prepend_upload_dir(s: &str) -> String {
-    format!("uploads/{}", s)
+/// Cleans up a client-supplied filename: only the part after the last path
+/// separator is kept, control characters are removed and names longer than
+/// 255 bytes are shortened, preserving their extension where possible.
+pub fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+
+    let name = match name {
+        "" | "." | ".." => "upload",
+        _ => name,
+    };
+
+    if name.len() <= MAX_FILENAME_LEN {
+        return name.to_owned();
+    }
+
+    let ext = match name.rfind('.') {
+        Some(i) if name.len() - i <= 16 => &name[i..],
+        _ => "",
+    };
+    let mut end = MAX_FILENAME_LEN - ext.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], ext)
+}
+
+/// Builds a `Content-Disposition` value as per RFC 6266, with a quoted ASCII
+/// fallback in `filename` and the exact name in `filename*`.
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, utf8_percent_encode(filename, ATTR_CHAR))
 }
 
 pub fn new_random_uuid() -> String {
     Uuid::new_v4().to_string()
 }
 
+pub fn is_uuid(s: &str) -> bool {
+    Uuid::parse_str(s).is_ok()
+}
+
+pub fn new_deletion_token() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+pub fn new_api_key() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(40)
+        .collect()
+}
+
+/// Deletion tokens and API keys are only ever persisted as their SHA-256 digest.
+pub fn hash_token(token: &str) -> Vec<u8> {
+    sha2::Sha256::digest(token.as_bytes()).to_vec()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses a time to live such as `30d`, `12h`, `15m`, `45s` or a plain
+/// number of seconds, returning `None` for `never` or `0`.
+pub fn parse_ttl(s: &str) -> Result<Option<u64>, String> {
+    let s = s.trim();
+
+    if s == "never" {
+        return Ok(None);
+    }
+
+    let (digits, factor) = match s.char_indices().last() {
+        Some((i, 'w')) => (&s[..i], 7 * 24 * 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 's')) => (&s[..i], 1),
+        _ => (s, 1),
+    };
+
+    let n: u64 = digits.parse()
+        .map_err(|_| format!("invalid ttl '{}', expected e.g. 30d, 12h, 15m or never", s))?;
+
+    match n.checked_mul(factor) {
+        Some(0) => Ok(None),
+        Some(secs) => Ok(Some(secs)),
+        None => Err(format!("ttl '{}' is too large", s)),
+    }
+}
+
+/// Parses a size such as `500M`, `2G`, `64k` or a plain number of bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+
+    let (digits, factor) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1_000),
+        Some((i, 'M')) => (&s[..i], 1_000_000),
+        Some((i, 'G')) => (&s[..i], 1_000_000_000),
+        _ => (s, 1),
+    };
+
+    let n: u64 = digits.parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 500M, 2G or 64k", s))?;
+
+    n.checked_mul(factor)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
 pub fn cleanup(config: &Config) {
-    if let Err(_) = fs::remove_file(config.socket_path.to_string()) {
-        eprintln!("failed to cleanup socket {}", config.socket_path);
+    for addr in config.listen.iter().chain(&config.metrics_listen) {
+        if let ListenAddr::Unix(path) = addr {
+            if fs::remove_file(path).is_err() {
+                warn!("failed to clean up socket {}", path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_paths_and_control_characters() {
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\cat.gif"), "cat.gif");
+        assert_eq!(sanitize_filename("../../cat\r\n.gif"), "cat.gif");
+        assert_eq!(sanitize_filename("  spaced out.txt \t"), "spaced out.txt");
+        assert_eq!(sanitize_filename("dir/"), "upload");
+        assert_eq!(sanitize_filename(""), "upload");
+        assert_eq!(sanitize_filename("."), "upload");
+        assert_eq!(sanitize_filename("a/.."), "upload");
+    }
+
+    #[test]
+    fn truncates_long_filenames() {
+        let name = sanitize_filename(&format!("{}.tar.gz", "a".repeat(300)));
+        assert_eq!(name.len(), MAX_FILENAME_LEN);
+        assert!(name.ends_with("a.gz"));
+
+        let long_ext = format!("a.{}", "b".repeat(300));
+        assert_eq!(sanitize_filename(&long_ext), long_ext[..MAX_FILENAME_LEN]);
+
+        let name = sanitize_filename(&format!("{}.txt", "é".repeat(200)));
+        assert_eq!(name, format!("{}.txt", "é".repeat(125)));
+    }
+
+    #[test]
+    fn builds_content_disposition() {
+        assert_eq!(content_disposition("inline", "cat.gif"),
+            "inline; filename=\"cat.gif\"; filename*=UTF-8''cat.gif");
+        assert_eq!(content_disposition("attachment", "say \"hi\"\\ü.txt"),
+            "attachment; filename=\"say _hi___.txt\"; \
+             filename*=UTF-8''say%20%22hi%22%5C%C3%BC.txt");
     }
 }